@@ -1,9 +1,8 @@
 use std::collections;
 use std::ffi;
 use std::fs;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Seek, Write};
 use std::path;
-use uuid;
 
 use anyhow::{anyhow, bail, Context};
 use bstr::{BStr, BString, ByteSlice, ByteVec};
@@ -17,10 +16,58 @@ type SeqNums = Vec<SeqNum>;
 const STATUS_OK: i32 = 0;
 const STATUS_ERROR: i32 = 1;
 const STATUS_EMPTY_BUNDLE: i32 = 3;
+const STATUS_MASS_CHANGE: i32 = 4;
+const STATUS_WRONG_REPO_ID: i32 = 5;
+const STATUS_MISSING_REPO_ID: i32 = 6;
+const STATUS_BASIS_MISSING: i32 = 7;
+const STATUS_BASIS_MISSING_STANDALONE: i32 = 8;
+const STATUS_MISSING_PREREQS: i32 = 9;
+const STATUS_MISSING_OREFS: i32 = 10;
+const STATUS_BASIS_UNAVAILABLE: i32 = 11;
+const STATUS_LOCKED: i32 = 12;
+
+const IBUNDLE_FORMAT_V2: &[u8] = b"# v2 git ibundle";
+const REPO_META_FORMAT_V1: &[u8] = b"# v1 repo meta";
+
+// Number of sequence numbers `git-ibundle clean` retains by default.
+const DEFAULT_KEEP: u64 = 20;
+
+// Type (and default, if any) of a Git configuration key.
+#[derive(Clone, Copy)]
+enum ConfigKind {
+    Path,
+    String(&'static str),
+    Bool(bool),
+    U64(Option<u64>),
+    MultiVar,
+}
 
-const IBUNDLE_FORMAT_V2: &'static [u8] = b"# v2 git ibundle";
-const REPO_META_FORMAT_V1: &'static [u8] = b"# v1 repo meta";
-const GIT_BUNDLE_FORMAT_V2: &'static [u8] = b"# v2 git bundle";
+// Git configuration understood by git-ibundle.
+const CONFIG_KEYS: &[(&str, ConfigKind)] = &[
+    ("ibundle.stateDir", ConfigKind::Path),
+    ("ibundle.outputDir", ConfigKind::Path),
+    ("ibundle.outbox", ConfigKind::Path),
+    (
+        "ibundle.outboxTemplate",
+        ConfigKind::String(DEFAULT_OUTBOX_TEMPLATE),
+    ),
+    ("ibundle.standalone", ConfigKind::Bool(false)),
+    ("ibundle.keep", ConfigKind::U64(Some(DEFAULT_KEEP))),
+    ("ibundle.maxRemovedPercent", ConfigKind::U64(None)),
+    ("ibundle.maxForcedRefs", ConfigKind::U64(None)),
+    ("ibundle.fsckObjects", ConfigKind::Bool(false)),
+    ("ibundle.verifySignatures", ConfigKind::Bool(false)),
+    ("ibundle.allowedSignersFile", ConfigKind::Path),
+    ("ibundle.identityPolicy", ConfigKind::Path),
+    ("ibundle.requiredApprovals", ConfigKind::U64(Some(0))),
+    ("ibundle.reviewersFile", ConfigKind::Path),
+    ("ibundle.archiveRefs", ConfigKind::Bool(false)),
+    ("ibundle.protectedRefs", ConfigKind::MultiVar),
+    ("ibundle.fastForwardOnly", ConfigKind::MultiVar),
+];
+
+// Refs below this prefix are local to a repository and never mirrored.
+const ARCHIVE_REF_PREFIX: &[u8] = b"refs/ibundle-archive/";
 
 fn quoted<B: AsRef<BStr>>(s: B) -> String {
     let s = s.as_ref();
@@ -36,6 +83,61 @@ fn quoted_path<P: AsRef<std::path::Path>>(path: P) -> String {
     quoted(p.as_bytes())
 }
 
+// A failure with its own exit status (other than `STATUS_ERROR`).
+#[derive(Debug)]
+struct StatusError {
+    status: i32,
+    message: String,
+}
+
+impl StatusError {
+    fn new(status: i32, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// Exit status for a failed command.
+fn error_status(e: &anyhow::Error) -> i32 {
+    if let Some(status_error) = e.downcast_ref::<StatusError>() {
+        return status_error.status;
+    }
+    let locked = e.chain().any(|cause| {
+        cause
+            .downcast_ref::<git2::Error>()
+            .is_some_and(|e| e.code() == git2::ErrorCode::Locked)
+            || is_gitoxide_locked(cause)
+    });
+    if locked {
+        STATUS_LOCKED
+    } else {
+        STATUS_ERROR
+    }
+}
+
+#[cfg(feature = "gitoxide")]
+fn is_gitoxide_locked(cause: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        cause.downcast_ref::<gix::lock::acquire::Error>(),
+        Some(gix::lock::acquire::Error::PermanentlyLocked { .. })
+    )
+}
+
+#[cfg(not(feature = "gitoxide"))]
+fn is_gitoxide_locked(_cause: &(dyn std::error::Error + 'static)) -> bool {
+    false
+}
+
 fn name_to_string(name: impl AsRef<BStr>) -> AResult<String> {
     if let Ok(s) = name.as_ref().to_str() {
         Ok(s.to_string())
@@ -70,59 +172,16 @@ fn create_writer<P: AsRef<std::path::Path>>(
     Ok(io::BufWriter::new(create_file(path)?))
 }
 
-fn read_bytes_until<R: io::Read, F>(
-    reader: &mut io::BufReader<R>,
-    bline: &mut Vec<u8>,
-    is_terminator: F,
-) -> io::Result<usize>
-where
-    F: Fn(u8) -> bool,
-{
-    bline.clear();
-    let mut read = 0;
-    loop {
-        let done;
-        let used;
-
-        {
-            let available = match reader.fill_buf() {
-                Ok(available) => available,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::Interrupted {
-                        continue;
-                    }
-                    return Err(e);
-                }
-            };
-
-            if let Some(pos) = available.iter().position(|&c| is_terminator(c))
-            {
-                done = true;
-                used = pos + 1;
-            } else {
-                done = false;
-                used = available.len();
-            }
-            bline.extend_from_slice(&available[..used]);
-        }
-        reader.consume(used);
-        read += used;
-        if done || used == 0 {
-            return Ok(read);
-        }
-    }
-}
-
 fn read_bline(f: &mut impl io::BufRead, line: &mut BString) -> AResult<usize> {
     line.clear();
     f.read_until(b'\n', line)?;
-    if line.ends_with(&[b'\n']) {
+    if line.ends_with(b"\n") {
         line.pop();
     }
     Ok(line.len())
 }
 
-fn bstr_pop_word<'a>(bstr: &'a BStr) -> (&'a BStr, &'a BStr) {
+fn bstr_pop_word(bstr: &BStr) -> (&BStr, &BStr) {
     if let Some((word, rest)) = bstr.split_once_str(b" ") {
         (word.as_bstr(), rest.as_bstr())
     } else {
@@ -191,6 +250,43 @@ fn oid_bstr_parse(bstr: &BStr) -> AResult<(git2::Oid, BString)> {
     }
 }
 
+// Matches `text` against glob `pattern`.  `?` matches any single character
+// except `/`, `*` matches any sequence of characters except `/`, and `**`
+// matches any sequence of characters (including `/`).  A leading `**/` also
+// matches zero directories, so `**/*.pem` matches `key.pem`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.starts_with(b"**") => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&b'/') && glob_match(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => {
+            !text.is_empty()
+                && text[0] != b'/'
+                && glob_match(&pattern[1..], &text[1..])
+        }
+        Some(&c) => {
+            text.first() == Some(&c) && glob_match(&pattern[1..], &text[1..])
+        }
+    }
+}
+
 // Deletes `file_path` when `FileDeleter` is dropped.
 struct FileDeleter {
     file_path: Option<path::PathBuf>,
@@ -212,6 +308,32 @@ impl Drop for FileDeleter {
     }
 }
 
+// Recursively deletes `dir_path` when `DirDeleter` is dropped.
+struct DirDeleter {
+    dir_path: Option<path::PathBuf>,
+}
+
+impl DirDeleter {
+    fn new<P: AsRef<path::Path>>(dir_path: P) -> Self {
+        Self {
+            dir_path: Some(dir_path.as_ref().to_path_buf()),
+        }
+    }
+}
+
+impl Drop for DirDeleter {
+    fn drop(&mut self) {
+        if let Some(dir_path) = self.dir_path.take() {
+            // Repositories within (e.g., quarantines) may be re-created later.
+            #[cfg(feature = "gitoxide")]
+            if let Ok(dir_path) = fs::canonicalize(&dir_path) {
+                gitoxide::forget_repos_within(&dir_path);
+            }
+            fs::remove_dir_all(&dir_path).ok();
+        }
+    }
+}
+
 //////////////////////////////////////////////////////////////////////////////
 
 /// Git offline incremental mirroring via ibundle files
@@ -222,6 +344,32 @@ struct Cli {
     #[command(flatten)]
     #[command(next_display_order = 10000)]
     verbose: clap_verbosity_flag::Verbosity<clap_verbosity_flag::InfoLevel>,
+
+    /// Output format
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text
+    )]
+    #[arg(display_order = 10000)]
+    format: OutputFormat,
+
+    /// Directory for git-ibundle state [default: <gitdir>/ibundle]
+    #[arg(long, global = true, value_name = "DIR")]
+    #[arg(display_order = 10000)]
+    state_dir: Option<path::PathBuf>,
+
+    /// Repository backend [default: libgit2]
+    #[arg(long, global = true, value_enum, value_name = "BACKEND")]
+    #[arg(display_order = 10000)]
+    backend: Option<BackendKind>,
+
+    /// Repository to operate on [default: current directory]
+    #[arg(short = 'C', long = "repo", global = true, value_name = "REPO")]
+    #[arg(display_order = 10000)]
+    repo: Option<path::PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -230,7 +378,11 @@ struct Cli {
 struct CreateArgs {
     /// ibundle file to create
     #[arg(value_name = "IBUNDLE_FILE")]
-    ibundle_path: path::PathBuf,
+    ibundle_path: Option<path::PathBuf>,
+
+    /// Create a named ibundle in outbox directory DIR
+    #[arg(long, value_name = "DIR", conflicts_with("ibundle_path"))]
+    outbox: Option<path::PathBuf>,
 
     /// Choose alternate basis sequence number
     #[arg(long)]
@@ -251,6 +403,10 @@ struct CreateArgs {
     )]
     standalone: bool,
 
+    /// Don't make the ibundle standalone (overriding `ibundle.standalone`)
+    #[arg(long, conflicts_with_all(["standalone", "basis_current"]))]
+    no_standalone: bool,
+
     /// Allow creation of an empty ibundle
     #[arg(
         long,
@@ -261,14 +417,50 @@ struct CreateArgs {
         )
     )]
     allow_empty: bool,
+
+    /// Include only refs matching glob PATTERN (may be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    include_refs: Vec<String>,
+
+    /// Leave out refs matching glob PATTERN (may be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    exclude_refs: Vec<String>,
+
+    /// Refuse to create the ibundle if new content violates policy file
+    #[arg(long, value_name = "POLICY_FILE")]
+    policy: Option<path::PathBuf>,
+
+    /// Write a human-reviewable transfer manifest
+    #[arg(long, value_name = "MANIFEST_FILE")]
+    manifest: Option<path::PathBuf>,
+
+    /// Allow removing or force-moving refs beyond configured thresholds
+    #[arg(long)]
+    allow_mass_changes: bool,
+
+    /// Report ref updates in machine-readable form
+    #[arg(long)]
+    porcelain: bool,
 }
 
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Debug, Clone)]
 struct FetchArgs {
     /// ibundle file to fetch
     #[arg(value_name = "IBUNDLE_FILE")]
     ibundle_path: path::PathBuf,
 
+    /// IBUNDLE_FILE is a multi-repository archive
+    #[arg(long)]
+    archive: bool,
+
+    /// Directory of repositories for --archive [default: current directory]
+    #[arg(long, value_name = "DIR", requires = "archive")]
+    repos_dir: Option<path::PathBuf>,
+
+    /// File mapping repo_ids to repository paths
+    #[arg(long, value_name = "REPO_MAP_FILE", requires = "archive")]
+    repo_map: Option<path::PathBuf>,
+
     /// Perform a trial fetch without making changes to the repository
     #[arg(long)]
     dry_run: bool,
@@ -276,6 +468,37 @@ struct FetchArgs {
     /// Force fetch operation
     #[arg(long)]
     force: bool,
+
+    #[command(flatten)]
+    checks: FetchCheckArgs,
+
+    /// Report ref updates in machine-readable form
+    #[arg(long)]
+    porcelain: bool,
+}
+
+// Fetch checks shared by `fetch` and `watch`.
+#[derive(clap::Args, Debug, Clone, Default)]
+struct FetchCheckArgs {
+    /// Check all objects in the ibundle's pack before fetching
+    #[arg(long)]
+    fsck_objects: bool,
+
+    /// Require trusted signatures on all newly fetched commits and tags
+    #[arg(long)]
+    verify_signatures: bool,
+
+    /// Refuse newly fetched commits whose identities violate policy file
+    #[arg(long, value_name = "IDENTITY_POLICY_FILE")]
+    identity_policy: Option<path::PathBuf>,
+
+    /// Allow removing or force-moving refs beyond configured thresholds
+    #[arg(long)]
+    allow_mass_changes: bool,
+
+    /// Archive removed and force-moved refs under refs/ibundle-archive/
+    #[arg(long)]
+    archive_refs: bool,
 }
 
 #[derive(clap::Args, Debug)]
@@ -283,19 +506,147 @@ struct ShowArgs {
     /// ibundle file to examine
     #[arg(value_name = "IBUNDLE_FILE")]
     ibundle_path: path::PathBuf,
+
+    /// Write a human-reviewable transfer manifest
+    #[arg(long, value_name = "MANIFEST_FILE")]
+    manifest: Option<path::PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ApproveArgs {
+    /// ibundle file to approve
+    #[arg(value_name = "IBUNDLE_FILE")]
+    ibundle_path: path::PathBuf,
+
+    /// Reviewer's SSH key for signing the approval
+    #[arg(long, value_name = "KEY_FILE")]
+    key: path::PathBuf,
 }
 
 #[derive(clap::Args, Debug)]
 struct StatusArgs {}
 
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    /// Directory where ibundles arrive
+    #[arg(long, value_name = "DIR")]
+    inbox: path::PathBuf,
+
+    /// Process the inbox once and exit
+    #[arg(long)]
+    once: bool,
+
+    /// Seconds between inbox scans
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    interval: u64,
+
+    /// Seconds a file must remain unmodified to be considered complete
+    #[arg(long, value_name = "SECS", default_value = "5")]
+    settle: u64,
+
+    #[command(flatten)]
+    checks: FetchCheckArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct OutboxArgs {
+    #[command(subcommand)]
+    command: OutboxCommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum OutboxCommands {
+    /// Delete acknowledged and superseded ibundles
+    Prune(OutboxPruneArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct OutboxPruneArgs {
+    /// Outbox directory
+    #[arg(value_name = "DIR")]
+    outbox_path: path::PathBuf,
+
+    /// Destination has fetched sequence numbers through SEQ_NUM
+    #[arg(long, value_name = "SEQ_NUM")]
+    acknowledged: Option<SeqNum>,
+
+    /// Report what would be deleted without deleting anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ArchiveArgs {
+    #[command(subcommand)]
+    command: ArchiveCommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ArchiveCommands {
+    /// Combine ibundles from many repositories into one archive
+    Create(ArchiveCreateArgs),
+
+    /// List the ibundles in an archive
+    List(ArchiveListArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ArchiveCreateArgs {
+    /// Archive file to create
+    #[arg(value_name = "ARCHIVE_FILE")]
+    archive_path: path::PathBuf,
+
+    /// ibundle files to include
+    #[arg(value_name = "IBUNDLE_FILE", required = true)]
+    ibundle_paths: Vec<path::PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ArchiveListArgs {
+    /// Archive file to list
+    #[arg(value_name = "ARCHIVE_FILE")]
+    archive_path: path::PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct FleetArgs {
+    #[command(subcommand)]
+    command: FleetCommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum FleetCommands {
+    /// Create an ibundle for each repository in a fleet manifest
+    Create(FleetRunArgs),
+
+    /// Fetch an ibundle for each repository in a fleet manifest
+    Fetch(FleetRunArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct FleetRunArgs {
+    /// Fleet manifest file
+    #[arg(value_name = "FLEET_MANIFEST")]
+    manifest_path: path::PathBuf,
+
+    /// Number of repositories to process in parallel
+    #[arg(short, long, default_value_t = 4,
+        value_parser = clap::value_parser!(u64).range(1..)
+        )]
+    jobs: u64,
+}
+
 #[derive(clap::Args, Debug)]
 struct CleanArgs {
-    /// Number of sequence numbers to retain
+    /// Number of sequence numbers to retain [default: 20]
     #[arg(long,
-        default_value = "20",
         value_parser = clap::value_parser!(u64).range(1..)
         )]
-    keep: u64,
+    keep: Option<u64>,
+
+    /// Remove archive refs for sequence numbers less than SEQ_NUM
+    #[arg(long, value_name = "SEQ_NUM")]
+    archive_older_than: Option<SeqNum>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -309,11 +660,43 @@ enum Commands {
     /// Show details of an ibundle
     Show(ShowArgs),
 
+    /// Approve an ibundle for fetching
+    Approve(ApproveArgs),
+
     /// Report status
     Status(StatusArgs),
 
     /// Cleanup old sequence numbers
     Clean(CleanArgs),
+
+    /// Manage an outbox directory of ibundles
+    Outbox(OutboxArgs),
+
+    /// Fetch ibundles as they arrive in an inbox directory
+    Watch(WatchArgs),
+
+    /// Manage multi-repository archives of ibundles
+    Archive(ArchiveArgs),
+
+    /// Create or fetch across the repositories in a fleet manifest
+    Fleet(FleetArgs),
+}
+
+impl Commands {
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Create(_) => "create",
+            Commands::Fetch(_) => "fetch",
+            Commands::Show(_) => "show",
+            Commands::Approve(_) => "approve",
+            Commands::Status(_) => "status",
+            Commands::Clean(_) => "clean",
+            Commands::Outbox(_) => "outbox",
+            Commands::Watch(_) => "watch",
+            Commands::Archive(_) => "archive",
+            Commands::Fleet(_) => "fleet",
+        }
+    }
 }
 
 //////////////////////////////////////////////////////////////////////////////
@@ -404,50 +787,215 @@ fn repo_open<P: AsRef<std::path::Path>>(
     Ok(repo)
 }
 
-fn repo_state_root_path(repo: &git2::Repository) -> path::PathBuf {
-    repo.path().join("ibundle")
+fn repo_config_bool(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Option<bool>> {
+    match repo.config()?.get_bool(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .with_context(|| format!("invalid boolean for config {}", name)),
+    }
+}
+
+fn repo_config_i64(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Option<i64>> {
+    match repo.config()?.get_i64(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .with_context(|| format!("invalid integer for config {}", name)),
+    }
+}
+
+fn repo_config_u64(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Option<u64>> {
+    match repo_config_i64(repo, name)? {
+        Some(value) => match u64::try_from(value) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => bail!("invalid negative config {} ({})", name, value),
+        },
+        None => Ok(None),
+    }
+}
+
+// Directory of the repository proper: its working tree, or the Git directory
+// itself for a bare repository.
+fn repo_dir_path(repo: &git2::Repository) -> &path::Path {
+    repo.workdir().unwrap_or_else(|| repo.path())
+}
+
+fn repo_config_string(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Option<String>> {
+    match repo.config()?.get_string(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("invalid config {}", name)),
+    }
+}
+
+fn repo_config_path(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Option<path::PathBuf>> {
+    match repo.config()?.get_path(name) {
+        // Relative paths are relative to the repository directory.
+        Ok(value) => Ok(Some(repo_dir_path(repo).join(value))),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => {
+            Err(e).with_context(|| format!("invalid path for config {}", name))
+        }
+    }
+}
+
+// Effective values of configuration `name` of type `kind`, as git-ibundle
+// uses them (parsed, with relative paths resolved and defaults applied), and
+// whether they come from a default rather than Git configuration.
+fn repo_config_effective(
+    repo: &git2::Repository,
+    name: &str,
+    kind: ConfigKind,
+) -> AResult<(Vec<BString>, bool)> {
+    let is_default = repo_config_multivar(repo, name)?.is_empty();
+    let values = match kind {
+        // The state directory may also come from `--state-dir`.
+        ConfigKind::Path if name == "ibundle.stateDir" => {
+            vec![Vec::from_path_lossy(&repo_state_root_path(repo)?)
+                .into_owned()
+                .into()]
+        }
+        ConfigKind::Path => repo_config_path(repo, name)?
+            .map(|path| Vec::from_path_lossy(&path).into_owned().into())
+            .into_iter()
+            .collect(),
+        ConfigKind::String(default) => vec![repo_config_string(repo, name)?
+            .unwrap_or_else(|| default.to_string())
+            .into()],
+        ConfigKind::Bool(default) => {
+            vec![
+                bool_as_bstr(repo_config_bool(repo, name)?.unwrap_or(default))
+                    .into(),
+            ]
+        }
+        ConfigKind::U64(default) => repo_config_u64(repo, name)?
+            .or(default)
+            .map(|value| value.to_string().into())
+            .into_iter()
+            .collect(),
+        ConfigKind::MultiVar => repo_config_multivar(repo, name)?,
+    };
+    Ok((values, is_default))
+}
+
+fn repo_config_multivar(
+    repo: &git2::Repository,
+    name: &str,
+) -> AResult<Vec<BString>> {
+    let mut values = Vec::new();
+    repo.config()?
+        .multivar(name, None)?
+        .for_each(|entry| values.push(BString::from(entry.value_bytes())))?;
+    Ok(values)
+}
+
+// Environment variable naming the state directory (like `--state-dir`).
+const STATE_DIR_ENV: &str = "GIT_IBUNDLE_STATE_DIR";
+
+// Absolute state directory from `--state-dir` or `GIT_IBUNDLE_STATE_DIR`,
+// chosen once by `run()`.
+static STATE_DIR: std::sync::OnceLock<Option<path::PathBuf>> =
+    std::sync::OnceLock::new();
+
+fn state_dir_path() -> Option<&'static path::Path> {
+    STATE_DIR
+        .get()
+        .expect("state directory is selected before use")
+        .as_deref()
+}
+
+fn select_state_dir(cli_state_dir: Option<&path::Path>) -> AResult<()> {
+    let state_dir_path = match cli_state_dir {
+        Some(state_dir_path) => Some(state_dir_path.as_os_str().to_owned()),
+        None => std::env::var_os(STATE_DIR_ENV).filter(|v| !v.is_empty()),
+    };
+    let state_dir_path = match state_dir_path {
+        Some(state_dir_path) => {
+            Some(std::env::current_dir()?.join(state_dir_path))
+        }
+        None => None,
+    };
+    if STATE_DIR.set(state_dir_path).is_err() {
+        bail!("state directory already selected");
+    }
+    Ok(())
+}
+
+// git-ibundle state lives in `<gitdir>/ibundle` unless a state directory is
+// configured (by `--state-dir`, `GIT_IBUNDLE_STATE_DIR`, or
+// `ibundle.stateDir`, in that order).  A configured state directory is shared
+// among repositories, each using a subdirectory keyed by its location.
+fn repo_state_root_path(repo: &git2::Repository) -> AResult<path::PathBuf> {
+    let state_dir_path = match state_dir_path() {
+        Some(state_dir_path) => Some(state_dir_path.to_path_buf()),
+        None => repo_config_path(repo, "ibundle.stateDir")?,
+    };
+    let state_dir_path = match state_dir_path {
+        Some(state_dir_path) => state_dir_path,
+        None => return Ok(repo.path().join("ibundle")),
+    };
+    use sha2::Digest;
+    let repo_dir_path = repo_dir_path(repo);
+    let digest = sha2::Sha256::digest(
+        <[u8]>::from_path(repo_dir_path).unwrap_or_default(),
+    );
+    let name = repo_dir_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    Ok(state_dir_path.join(format!("{}-{:.16x}", name, digest)))
 }
 
-fn repo_temp_dir_path(repo: &git2::Repository) -> path::PathBuf {
-    repo_state_root_path(repo).join("temp")
+fn repo_temp_dir_path(repo: &git2::Repository) -> AResult<path::PathBuf> {
+    Ok(repo_state_root_path(repo)?.join("temp"))
 }
 
 fn repo_mktemp(repo: &git2::Repository) -> AResult<path::PathBuf> {
-    let temp_dir_path = repo_temp_dir_path(repo);
+    let temp_dir_path = repo_temp_dir_path(repo)?;
     fs::create_dir_all(&temp_dir_path)?;
     Ok(temp_dir_path)
 }
 
-fn repo_meta_dir_path(repo: &git2::Repository) -> path::PathBuf {
-    repo_state_root_path(repo).join("repo_meta")
+fn repo_meta_dir_path(repo: &git2::Repository) -> AResult<path::PathBuf> {
+    Ok(repo_state_root_path(repo)?.join("repo_meta"))
 }
 
-fn repo_meta_path(repo: &git2::Repository, seq_num: SeqNum) -> path::PathBuf {
-    repo_meta_dir_path(&repo).join(&seq_num.to_string())
+fn repo_meta_path(
+    repo: &git2::Repository,
+    seq_num: SeqNum,
+) -> AResult<path::PathBuf> {
+    Ok(repo_meta_dir_path(repo)?.join(seq_num.to_string()))
 }
 
-fn repo_id_path(repo: &git2::Repository) -> path::PathBuf {
-    repo_state_root_path(repo).join("id")
+fn repo_id_path(repo: &git2::Repository) -> AResult<path::PathBuf> {
+    Ok(repo_state_root_path(repo)?.join("id"))
 }
 
 fn repo_orefs(repo: &git2::Repository) -> AResult<ORefs> {
-    let mut orefs = ORefs::new();
-    for r in repo.references()? {
-        let r = r?;
-        let oid = if let Some(oid) = r.target() {
-            oid
-        } else {
-            bail!("found non-direct ref kind {:?}", r.kind());
-        };
-        let name = RefName::from(r.name_bytes());
-        orefs.insert(name, oid);
-    }
+    let mut orefs = backend().refs(repo)?;
+    orefs.retain(|name, _| !name.starts_with(ARCHIVE_REF_PREFIX));
     Ok(orefs)
 }
 
 fn repo_is_empty(repo: &git2::Repository) -> AResult<bool> {
     let orefs = repo_orefs(repo)?;
-    Ok(orefs.len() == 0)
+    Ok(orefs.is_empty())
 }
 
 fn repo_find_missing_commits<'a>(
@@ -457,7 +1005,7 @@ fn repo_find_missing_commits<'a>(
     commits
         .into_iter()
         .filter_map(|(&commit_id, comment)| {
-            if !repo.find_commit(commit_id).is_ok() {
+            if !repo_has_oid(repo, commit_id) {
                 Some((commit_id, comment.clone()))
             } else {
                 None
@@ -466,17 +1014,95 @@ fn repo_find_missing_commits<'a>(
         .collect()
 }
 
-fn repo_remove_refs(
-    repo: &git2::Repository,
-    refs_to_remove: &collections::HashSet<BString>,
-) -> AResult<()> {
-    for res in repo.references()? {
-        let mut r = res?;
-        if refs_to_remove.contains(r.name_bytes().as_bstr()) {
-            r.delete()?;
+fn is_protected_ref(name: &BStr, protected_patterns: &[BString]) -> bool {
+    name != b"HEAD".as_bstr()
+        && protected_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+}
+
+// Returns `new_orefs` with refs matching `protected_patterns` kept at their
+// values in `old_orefs`, warning about each change that is ignored.
+fn protect_orefs(
+    new_orefs: ORefs,
+    old_orefs: &ORefs,
+    protected_patterns: &[BString],
+) -> ORefs {
+    let is_protected =
+        |name: &RefName| is_protected_ref(name.as_bstr(), protected_patterns);
+    let mut orefs = ORefs::new();
+    for (name, &oid) in new_orefs.iter() {
+        if !is_protected(name) {
+            orefs.insert(name.clone(), oid);
+        } else if old_orefs.get(name) != Some(&oid) {
+            log::warn!(
+                "ignoring ibundle change to protected ref {}",
+                quoted(name)
+            );
         }
     }
-    Ok(())
+    for (name, &oid) in old_orefs.iter() {
+        if is_protected(name) {
+            orefs.insert(name.clone(), oid);
+            if !new_orefs.contains_key(name) {
+                log::warn!(
+                    "ignoring ibundle removal of protected ref {}",
+                    quoted(name)
+                );
+            }
+        }
+    }
+    orefs
+}
+
+fn archive_ref_name(seq_num: SeqNum, name: &BStr) -> BString {
+    let mut archive_name = BString::from(ARCHIVE_REF_PREFIX);
+    archive_name.push_str(seq_num.to_string());
+    archive_name.push(b'/');
+    archive_name.push_str(name);
+    archive_name
+}
+
+// Ref updates saving the current value of each of `names` (from `old_orefs`)
+// under `refs/ibundle-archive/<seq_num>/`.
+fn archive_ref_updates<'a>(
+    seq_num: SeqNum,
+    old_orefs: &ORefs,
+    names: impl IntoIterator<Item = &'a RefName>,
+) -> Vec<(RefName, Option<git2::Oid>)> {
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let &oid = old_orefs.get(name)?;
+            Some((archive_ref_name(seq_num, name.as_bstr()), Some(oid)))
+        })
+        .collect()
+}
+
+// Parses the sequence number from an archive ref name.
+fn archive_ref_seq_num(name: &BStr) -> Option<SeqNum> {
+    let rest = name.strip_prefix(ARCHIVE_REF_PREFIX)?;
+    let (seq_num, _) = rest.split_once_str(b"/")?;
+    parse_seq_num(seq_num).ok()
+}
+
+// Removes archive refs for sequence numbers less than `seq_num`, returning
+// the number of refs removed.
+fn repo_expire_archive_refs(
+    repo: &git2::Repository,
+    seq_num: SeqNum,
+) -> AResult<usize> {
+    let updates = backend()
+        .refs(repo)?
+        .into_keys()
+        .filter(|name| {
+            archive_ref_seq_num(name.as_bstr())
+                .is_some_and(|archive_seq_num| archive_seq_num < seq_num)
+        })
+        .map(|name| (name, None))
+        .collect::<Vec<_>>();
+    backend().update_refs(repo, &updates, "git-ibundle: archive")?;
+    Ok(updates.len())
 }
 
 //////////////////////////////////////////////////////////////////////////////
@@ -591,430 +1217,1824 @@ impl RepoMeta {
     }
 }
 
-fn git_bundle_header_read<R: io::BufRead>(
-    reader: &mut R,
-) -> AResult<(Commits, ORefs)> {
-    let mut bline = BString::from("");
-    let mut prereqs = Commits::new();
-    let mut orefs = ORefs::new();
-    read_bline(reader, &mut bline)?;
+fn repo_peel_commit(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+) -> Option<git2::Commit<'_>> {
+    repo.find_object(oid, None)
+        .and_then(|obj| obj.peel_to_commit())
+        .ok()
+}
 
-    if bline != GIT_BUNDLE_FORMAT_V2 {
-        bail!("not a V2 bundle file");
-    }
-    while read_bline(reader, &mut bline)? > 0 {
-        if bline[0] == b'-' {
-            let (oid, comment) = oid_bstr_parse(bline[1..].as_bstr())?;
-            prereqs.insert(oid, comment);
-        } else {
-            let (oid, name) = oid_bstr_parse(bline.as_bstr())?;
-            orefs.insert(name, oid);
-        }
-    }
-    Ok((prereqs, orefs))
+//////////////////////////////////////////////////////////////////////////////
+
+// The repository operations at the core of mirroring go through a `Backend`,
+// chosen once by `run()` from `--backend` (or `GIT_IBUNDLE_BACKEND`).
+// Everything else uses libgit2 directly.
+const BACKEND_ENV: &str = "GIT_IBUNDLE_BACKEND";
+
+static BACKEND: std::sync::OnceLock<&'static dyn Backend> =
+    std::sync::OnceLock::new();
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BackendKind {
+    #[default]
+    Libgit2,
+    // Requires the `gitoxide` cargo feature.
+    Gitoxide,
 }
 
-fn git_bundle_header_write<'p, 'o, W: io::Write>(
-    writer: &mut W,
-    prereqs: impl IntoIterator<Item = CommitsItem<'p>>,
-    orefs: impl IntoIterator<Item = ORefsItem<'o>>,
-) -> AResult<()> {
-    writer.write_all(GIT_BUNDLE_FORMAT_V2)?;
-    writer.write_all(b"\n")?;
-    for (commit_id, comment) in prereqs.into_iter() {
-        writer.write_all(b"-")?;
-        write_oid_bstr_bline(writer, commit_id, comment.as_bstr())?;
+type PackWriteFn<'r> = Box<dyn FnOnce(&mut dyn io::Write) -> AResult<()> + 'r>;
+
+// A pack planned by `Backend::plan_pack()`, ready to be written.
+struct PackPlan<'r> {
+    // Commits the destination must already have.
+    prereqs: Commits,
+    // Orefs whose objects are in the pack.
+    packed_orefs: ORefs,
+    write: PackWriteFn<'r>,
+}
+
+trait Backend: Sync {
+    fn name(&self) -> &'static str;
+
+    // Returns every ref in `repo`, which must all be direct.
+    fn refs(&self, repo: &git2::Repository) -> AResult<ORefs>;
+
+    fn has_object(&self, repo: &git2::Repository, oid: git2::Oid) -> bool;
+
+    // Plans a pack holding the objects reachable from `bundle_orefs` but not
+    // from `excluded_oids` (as `git bundle create` would).
+    fn plan_pack<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        bundle_orefs: &ORefs,
+        excluded_oids: &collections::HashSet<&git2::Oid>,
+    ) -> AResult<PackPlan<'r>>;
+
+    // Streams the pack in `pack_file` (from its current position) into the
+    // object database of `repo`, indexing it as a new pack.  Thin-pack deltas
+    // are resolved against objects already in `repo`.
+    fn index_pack(
+        &self,
+        repo: &git2::Repository,
+        pack_file: fs::File,
+    ) -> AResult<()>;
+
+    // Sets each ref in `updates` to its oid, deleting (if present) those
+    // without one.
+    fn update_refs(
+        &self,
+        repo: &git2::Repository,
+        updates: &[(RefName, Option<git2::Oid>)],
+        log_message: &str,
+    ) -> AResult<()>;
+}
+
+fn backend_kind(cli_kind: Option<BackendKind>) -> AResult<BackendKind> {
+    use clap::ValueEnum;
+    if let Some(kind) = cli_kind {
+        return Ok(kind);
     }
-    for (name, oid) in orefs.into_iter() {
-        write_oid_bstr_bline(writer, oid, name.as_bstr())?;
+    match std::env::var(BACKEND_ENV) {
+        Ok(value) if !value.is_empty() => {
+            match BackendKind::from_str(&value, true) {
+                Ok(kind) => Ok(kind),
+                Err(_) => bail!(
+                    "unknown backend {} in {}",
+                    quoted(value.as_bytes()),
+                    BACKEND_ENV
+                ),
+            }
+        }
+        _ => Ok(BackendKind::default()),
     }
-    writer.write_all(b"\n")?;
-    Ok(())
 }
 
-fn handle_bundle_create_stderr<R: io::Read>(
-    stderr: &mut io::BufReader<R>,
-) -> io::Result<bool> {
-    let mut bundle_empty = false;
-    let mut bline = Vec::new();
-    loop {
-        read_bytes_until(stderr, &mut bline, |b| b == b'\n' || b == b'\r')?;
-        if bline.len() == 0 {
-            break;
-        }
-        if bline
-            .as_bstr()
-            .contains_str("Refusing to create empty bundle")
-        {
-            bundle_empty = true;
-        } else {
-            io::stderr().write_all(&bline)?;
+fn backend_for(kind: BackendKind) -> AResult<&'static dyn Backend> {
+    match kind {
+        BackendKind::Libgit2 => Ok(&Libgit2Backend),
+        #[cfg(feature = "gitoxide")]
+        BackendKind::Gitoxide => Ok(&gitoxide::GitoxideBackend),
+        #[cfg(not(feature = "gitoxide"))]
+        BackendKind::Gitoxide => {
+            bail!("git-ibundle was built without the `gitoxide` feature")
         }
     }
-    Ok(bundle_empty)
 }
 
-fn git_bundle_create_stdin(
-    bundle_path: &path::Path,
-    stdin: fs::File,
-) -> AResult<()> {
-    let mut args: Vec<ffi::OsString> = vec!["bundle".into(), "create".into()];
-    if !log_enabled!(Level::Info) {
-        args.push("-q".into());
+// Returns the backend selected by `run()`.
+fn backend() -> &'static dyn Backend {
+    *BACKEND.get().expect("backend is selected before use")
+}
+
+// libgit2 holds each locked ref's lock file open until its transaction
+// commits, so allow as many open files as permitted.
+#[cfg(unix)]
+fn raise_open_files_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: `limit` is a valid `rlimit` for both calls.  Failure merely
+    // leaves the limit as it was.
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0
+            && limit.rlim_cur < limit.rlim_max
+        {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
     }
-    args.push(bundle_path.as_os_str().into());
-    args.push("--stdin".into());
+}
 
-    let mut child = std::process::Command::new("git")
-        .args(args)
-        .stdin(stdin)
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
+#[cfg(not(unix))]
+fn raise_open_files_limit() {}
 
-    let mut stderr = io::BufReader::new(
-        child
-            .stderr
-            .take()
-            .expect("Command failed to provide `stderr`"),
-    );
+struct Libgit2Backend;
 
-    let bundle_empty = handle_bundle_create_stderr(&mut stderr)?;
-    let exit_status = child.wait()?;
+impl Backend for Libgit2Backend {
+    fn name(&self) -> &'static str {
+        "libgit2"
+    }
 
-    if bundle_empty {
-        // `empty_pack_bytes` comes from:
-        //   `git pack-objects --stdout < /dev/null > empty.pack`
-        let empty_pack_bytes = vec![
-            0x50, 0x41, 0x43, 0x4b, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
-            0x00, 0x02, 0x9d, 0x08, 0x82, 0x3b, 0xd8, 0xa8, 0xea, 0xb5, 0x10,
-            0xad, 0x6a, 0xc7, 0x5c, 0x82, 0x3c, 0xfd, 0x3e, 0xd3, 0x1e,
-        ];
+    fn refs(&self, repo: &git2::Repository) -> AResult<ORefs> {
+        let mut orefs = ORefs::new();
+        for r in repo.references()? {
+            let r = r?;
+            let oid = if let Some(oid) = r.target() {
+                oid
+            } else {
+                bail!("found non-direct ref kind {:?}", r.kind());
+            };
+            orefs.insert(RefName::from(r.name_bytes()), oid);
+        }
+        Ok(orefs)
+    }
 
-        let mut writer = create_writer(&bundle_path)?;
-        git_bundle_header_write(&mut writer, &Commits::new(), &ORefs::new())?;
-        writer.write_all(&empty_pack_bytes)?;
-    } else if !exit_status.success() {
-        bail!("failure in git bundle create");
+    fn has_object(&self, repo: &git2::Repository, oid: git2::Oid) -> bool {
+        repo.find_object(oid, None).is_ok()
     }
-    Ok(())
-}
 
-fn git_fetch_bundle(bundle_path: &path::Path, dry_run: bool) -> AResult<()> {
-    let mut args: Vec<ffi::OsString> = vec!["fetch".into(), "--force".into()];
-    if !log_enabled!(Level::Info) {
-        args.push("-q".into())
+    fn plan_pack<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        bundle_orefs: &ORefs,
+        excluded_oids: &collections::HashSet<&git2::Oid>,
+    ) -> AResult<PackPlan<'r>> {
+        let new_revwalk = || -> AResult<git2::Revwalk<'r>> {
+            let mut revwalk = repo.revwalk()?;
+            for &&oid in excluded_oids.iter() {
+                if let Some(commit) = repo_peel_commit(repo, oid) {
+                    revwalk.hide(commit.id())?;
+                }
+            }
+            for &oid in bundle_orefs.values() {
+                if let Some(commit) = repo_peel_commit(repo, oid) {
+                    revwalk.push(commit.id())?;
+                }
+            }
+            Ok(revwalk)
+        };
+        let new_commit_ids =
+            new_revwalk()?.collect::<Result<collections::HashSet<_>, _>>()?;
+
+        // Boundary commits are prerequisites.
+        let mut prereqs = Commits::new();
+        for &commit_id in new_commit_ids.iter() {
+            for parent in repo.find_commit(commit_id)?.parents() {
+                if !new_commit_ids.contains(&parent.id()) {
+                    prereqs
+                        .entry(parent.id())
+                        .or_insert_with(|| commit_comment(&parent));
+                }
+            }
+        }
+
+        let mut pack_builder = repo.packbuilder()?;
+        pack_builder.set_threads(0);
+        pack_builder.insert_walk(&mut new_revwalk()?)?;
+        let mut packed_orefs = ORefs::new();
+        for (name, &oid) in bundle_orefs.iter() {
+            let obj = repo.find_object(oid, None)?;
+            let packed = if obj.kind() == Some(git2::ObjectType::Commit) {
+                new_commit_ids.contains(&oid)
+            } else {
+                !excluded_oids.contains(&oid)
+            };
+            if packed {
+                packed_orefs.insert(name.clone(), oid);
+                // Tags (and refs to trees or blobs) are outside the walk.
+                let mut target = obj;
+                while let Some(tag) = target.as_tag() {
+                    pack_builder.insert_object(tag.id(), None)?;
+                    let tag_target = tag.target()?;
+                    target = tag_target;
+                }
+                if target.kind() != Some(git2::ObjectType::Commit) {
+                    pack_builder.insert_recursive(target.id(), None)?;
+                }
+            }
+            // We want every oref, even those whose commit is excluded by the
+            // basis, so such commits become prerequisites.
+            if let Some(commit) = repo_peel_commit(repo, oid) {
+                if !new_commit_ids.contains(&commit.id()) {
+                    prereqs
+                        .entry(commit.id())
+                        .or_insert_with(|| commit_comment(&commit));
+                }
+            }
+        }
+        Ok(PackPlan {
+            prereqs,
+            packed_orefs,
+            write: Box::new(move |writer| {
+                let mut write_result = Ok(());
+                let pack_result = pack_builder.foreach(|chunk| {
+                    write_result = writer.write_all(chunk);
+                    write_result.is_ok()
+                });
+                write_result?;
+                pack_result?;
+                Ok(())
+            }),
+        })
     }
-    if dry_run {
-        args.push("--dry-run".into())
+
+    fn index_pack(
+        &self,
+        repo: &git2::Repository,
+        mut pack_file: fs::File,
+    ) -> AResult<()> {
+        let odb = repo.odb()?;
+        let mut pack_writer = odb.packwriter()?;
+        io::copy(&mut pack_file, &mut pack_writer)
+            .and_then(|_| pack_writer.flush())
+            .context("failure indexing ibundle pack")?;
+        pack_writer
+            .commit()
+            .context("failure indexing ibundle pack")?;
+        Ok(())
     }
-    args.push(bundle_path.as_os_str().into());
-    args.push("*:*".into());
-    let mut child = std::process::Command::new("git").args(args).spawn()?;
-    let exit_status = child.wait()?;
-    if !exit_status.success() {
-        bail!("failure in git fetch");
+
+    fn update_refs(
+        &self,
+        repo: &git2::Repository,
+        updates: &[(RefName, Option<git2::Oid>)],
+        log_message: &str,
+    ) -> AResult<()> {
+        // Every ref is locked before any is changed, so a failure (e.g., a
+        // ref locked by a concurrent operation) leaves all refs unchanged.
+        raise_open_files_limit();
+        let mut transaction = repo.transaction()?;
+        for (name, oid) in updates.iter() {
+            let name = ref_name_str(name.as_bstr());
+            if oid.is_none() && repo.find_reference(&name).is_err() {
+                continue;
+            }
+            transaction.lock_ref(&name)?;
+            match oid {
+                Some(oid) => {
+                    transaction.set_target(&name, *oid, None, log_message)?
+                }
+                None => transaction.remove(&name)?,
+            }
+        }
+        transaction.commit()?;
+        Ok(())
     }
-    Ok(())
 }
 
-//////////////////////////////////////////////////////////////////////////////
+// A pure-Rust backend built on gitoxide.  Commit comments for prerequisites
+// still come from libgit2, so they match those of the default backend.
+#[cfg(feature = "gitoxide")]
+mod gitoxide {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
 
-fn repo_fetch(
-    repo: &git2::Repository,
-    prereqs: &Commits,
-    bundle_orefs: &ORefs,
-    mut pack_reader: impl io::Read,
-    dry_run: bool,
-) -> AResult<()> {
-    let temp_dir_path = repo_mktemp(repo)?;
-    let bundle_path = temp_dir_path.join("temp.bundle");
-    let bundle_path_deleter = FileDeleter::new(&bundle_path);
-    let mut bundle_file = fs::File::create(&bundle_path)?;
+    pub(super) struct GitoxideBackend;
 
-    git_bundle_header_write(&mut bundle_file, prereqs, bundle_orefs)?;
-    io::copy(&mut pack_reader, &mut bundle_file)?;
-    drop(pack_reader);
-    bundle_file.flush()?;
-    drop(bundle_file);
+    // Opening a repository reads all of its configuration, so repositories
+    // are kept open (per thread) for reuse.
+    const MAX_OPEN_REPOS: usize = 16;
 
-    git_fetch_bundle(&bundle_path, dry_run)?;
-    drop(bundle_path_deleter);
+    thread_local! {
+        static OPEN_REPOS: std::cell::RefCell<
+            collections::HashMap<path::PathBuf, gix::Repository>,
+        > = Default::default();
+    }
 
-    Ok(())
-}
+    fn open(repo: &git2::Repository) -> AResult<gix::Repository> {
+        OPEN_REPOS.with(|open_repos| {
+            let mut open_repos = open_repos.borrow_mut();
+            if let Some(grepo) = open_repos.get(repo.path()) {
+                return Ok(grepo.clone());
+            }
+            if open_repos.len() >= MAX_OPEN_REPOS {
+                open_repos.clear();
+            }
+            let grepo = gix::open(repo.path())?;
+            open_repos.insert(repo.path().to_path_buf(), grepo.clone());
+            Ok(grepo)
+        })
+    }
 
-// git2::Repository::set_head() is below:
-//
-//   pub fn set_head(&self, refname: &str) -> Result<(), Error> {
-//       let refname = CString::new(refname)?;
-//       unsafe {
-//           try_call!(raw::git_repository_set_head(self.raw, refname));
-//       }
-//       Ok(())
-//   }
-//
-// The passed-in `&str` value `refname` is not interpreted in any way; it's
-// simply passed along into `git_repository_set_head()`, a function that expects
-// raw bytes and does not require UTF8 semantics.
-//
-// Until git2 provides a `set_head_bytes()` function as requested in
-// <https://github.com/rust-lang/git2-rs/issues/925>, and provided in pull
-// request <https://github.com/rust-lang/git2-rs/pull/931>, the only way to
-// support non-utf8 head values with git2 is to use the unsafe conversion
-// `String::from_utf8_unchecked()`.
+    // Drops the open repositories within `dir_path`.
+    pub(super) fn forget_repos_within(dir_path: &path::Path) {
+        OPEN_REPOS.with(|open_repos| {
+            open_repos
+                .borrow_mut()
+                .retain(|repo_path, _| !repo_path.starts_with(dir_path));
+        });
+    }
 
-fn repo_set_head_ref(
-    repo: &git2::Repository,
-    head_ref: impl AsRef<BStr>,
-) -> AResult<()> {
-    let head_ref = head_ref.as_ref();
-    if let Ok(head_ref_str) = name_to_string(head_ref) {
-        repo.set_head(&head_ref_str)?;
-    } else {
-        // `head_ref` is non-utf8.
-        let head_ref_bytes = head_ref.to_vec();
-        // Safety: repo.set_head() does not interpret its argument as a
-        // utf8-string; it merely passed it along to the underlying library
-        // that's expecting raw bytes.
-        repo.set_head(&unsafe { String::from_utf8_unchecked(head_ref_bytes) })?;
+    fn gix_oid(oid: git2::Oid) -> gix::ObjectId {
+        gix::ObjectId::from_bytes_or_panic(oid.as_bytes())
     }
-    Ok(())
-}
 
-fn repo_has_oid(repo: &git2::Repository, oid: git2::Oid) -> bool {
-    repo.find_object(oid, None).is_ok()
-}
+    fn git2_oid(id: &gix::oid) -> AResult<git2::Oid> {
+        Ok(git2::Oid::from_bytes(id.as_bytes())?)
+    }
 
-fn repo_commit(
-    repo: &git2::Repository,
-    commit_id: git2::Oid,
-) -> AResult<git2::Commit> {
-    Ok(repo.find_object(commit_id, None)?.peel_to_commit()?)
-}
+    fn peel_commit(
+        repo: &gix::Repository,
+        id: gix::ObjectId,
+    ) -> Option<gix::ObjectId> {
+        Some(repo.find_object(id).ok()?.peel_to_commit().ok()?.id)
+    }
 
-fn commit_comment(commit: &git2::Commit) -> BString {
-    let comment = if let Some(summary) = commit.summary_bytes() {
-        BString::from(summary)
-    } else {
-        BString::from("")
-    };
-    comment
-}
+    fn add_prereq(
+        repo: &git2::Repository,
+        prereqs: &mut Commits,
+        id: &gix::oid,
+    ) -> AResult<()> {
+        let oid = git2_oid(id)?;
+        if let collections::btree_map::Entry::Vacant(entry) = prereqs.entry(oid)
+        {
+            entry.insert(commit_comment(&repo.find_commit(oid)?));
+        }
+        Ok(())
+    }
 
-fn repo_commit_id_comment(
-    repo: &git2::Repository,
-    commit_id: git2::Oid,
-) -> AResult<(git2::Oid, BString)> {
-    let commit = repo_commit(repo, commit_id)?;
-    Ok((commit.id(), commit_comment(&commit)))
-}
+    // Adds the objects reachable from `tree_id` to `seen`, also appending
+    // them to `out` if given.  Subtrees already in `seen` are skipped.
+    fn tree_objects(
+        repo: &gix::Repository,
+        tree_id: gix::ObjectId,
+        seen: &mut collections::HashSet<gix::ObjectId>,
+        mut out: Option<&mut Vec<gix::ObjectId>>,
+    ) -> AResult<()> {
+        let mut tree_ids = vec![tree_id];
+        while let Some(tree_id) = tree_ids.pop() {
+            if !seen.insert(tree_id) {
+                continue;
+            }
+            if let Some(out) = out.as_mut() {
+                out.push(tree_id);
+            }
+            let tree = repo.find_tree(tree_id)?;
+            for entry in tree.decode()?.entries.iter() {
+                let id = entry.oid.to_owned();
+                if entry.mode.is_tree() {
+                    tree_ids.push(id);
+                } else if !entry.mode.is_commit() && seen.insert(id) {
+                    // Submodule commits live elsewhere.
+                    if let Some(out) = out.as_mut() {
+                        out.push(id);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-fn repo_seq_nums(repo: &git2::Repository) -> AResult<SeqNums> {
-    let mut seq_nums = SeqNums::new();
-    let meta_dir_path = repo_meta_dir_path(&repo);
-    if let Ok(dir_iter) = fs::read_dir(&meta_dir_path) {
-        for entry in dir_iter {
-            if let Ok(seq_num) =
-                entry?.file_name().to_string_lossy().parse::<u64>()
+    fn write_pack(
+        repo: &gix::Repository,
+        ids: Vec<gix::ObjectId>,
+        writer: &mut dyn io::Write,
+    ) -> AResult<()> {
+        use gix_pack::data::output;
+        let mut db = repo.objects.clone().into_arc()?.into_inner();
+        // Packed entries are copied into the new pack as they are.
+        db.prevent_pack_unload();
+        let should_interrupt = AtomicBool::new(false);
+        // Unlike `objects_unthreaded()`, this notes where objects are packed,
+        // so existing deltas can be reused.
+        let (counts, _) = output::count::objects(
+            db.clone(),
+            Box::new(ids.into_iter().map(Ok)),
+            &gix::progress::Discard,
+            &should_interrupt,
+            output::count::objects::Options::default(),
+        )?;
+        let num_entries = u32::try_from(counts.len())?;
+        let entries = output::entry::iter_from_counts(
+            counts,
+            db,
+            Box::new(gix::progress::Discard),
+            output::entry::iter_from_counts::Options {
+                allow_thin_pack: false,
+                ..Default::default()
+            },
+        )
+        .map(|res| res.map(|(_, entries)| entries));
+        for res in output::bytes::FromEntriesIter::new(
+            entries,
+            writer,
+            num_entries,
+            gix_pack::data::Version::V2,
+            repo.object_hash(),
+        ) {
+            res?;
+        }
+        Ok(())
+    }
+
+    // Scans the pack read from `pack_file` for ref deltas, without keeping
+    // any object data.
+    fn has_ref_deltas(
+        pack_file: &fs::File,
+        repo: &gix::Repository,
+    ) -> AResult<bool> {
+        use gix_pack::data::input;
+        let entries = input::BytesToEntriesIter::new_from_header(
+            io::BufReader::new(pack_file),
+            input::Mode::AsIs,
+            input::EntryDataMode::Ignore,
+            repo.object_hash(),
+        )
+        .context("failure indexing ibundle pack")?;
+        for entry in entries {
+            let entry = entry.context("failure indexing ibundle pack")?;
+            if let gix_pack::data::entry::Header::RefDelta { .. } = entry.header
             {
-                seq_nums.push(seq_num);
+                return Ok(true);
             }
         }
+        Ok(false)
     }
-    seq_nums.sort_by(|a, b| b.cmp(a));
-    Ok(seq_nums)
-}
 
-fn repo_has_basis(repo: &git2::Repository, basis_seq_num: &SeqNum) -> bool {
-    if let Ok(seq_nums) = repo_seq_nums(&repo) {
-        seq_nums.contains(basis_seq_num)
-    } else {
-        false
+    // Indexes the pack at `pack_path` into `pack_dir_path` with each ref delta
+    // against a base earlier in the pack turned into an offset delta.  libgit2
+    // (and so the default backend) writes all deltas as ref deltas, but
+    // gitoxide's indexer looks up ref-delta bases only in the object database
+    // (`gix_pack::data::input::LookupRefDeltaObjectsIter`) and rejects any
+    // that remain (`gix_pack::index::write::Error::IteratorInvariantNoRefDelta`).
+    // Each object is decoded once for its id; compressed data is copied as is,
+    // and the converted pack goes straight to the indexer.
+    fn index_converted_pack(
+        repo: &gix::Repository,
+        pack_path: &path::Path,
+        pack_dir_path: &path::Path,
+    ) -> AResult<()> {
+        use gix::objs::Find;
+        use gix_pack::data::{
+            decode::entry::ResolvedBase, entry::Header, output,
+        };
+        let pack = gix_pack::data::File::at(pack_path, repo.object_hash())?;
+        // Pack offset and entry index of each object seen so far.
+        let mut id_locations = collections::HashMap::new();
+        let mut offset_indices = collections::HashMap::new();
+        let mut inflate = gix::features::zlib::Inflate::default();
+        let mut cache = gix_pack::cache::lru::StaticLinkedList::<64>::default();
+        let mut data = Vec::new();
+        let entries = pack.streaming_iter()?.enumerate().map(
+            |(index, entry)| -> AResult<_> {
+                let entry = entry?;
+                let offset = entry.pack_offset;
+                let outcome = pack.decode_entry(
+                    pack.entry(offset)?,
+                    &mut data,
+                    &mut inflate,
+                    &|id, buf| match id_locations.get(id) {
+                        Some(&(base_offset, _)) => pack
+                            .entry(base_offset)
+                            .ok()
+                            .map(ResolvedBase::InPack),
+                        None => {
+                            let obj = repo.objects.try_find(id, buf).ok()??;
+                            Some(ResolvedBase::OutOfPack {
+                                kind: obj.kind,
+                                end: obj.data.len(),
+                            })
+                        }
+                    },
+                    &mut cache,
+                )?;
+                let id = gix::objs::compute_hash(
+                    repo.object_hash(),
+                    outcome.kind,
+                    &data,
+                )?;
+                let kind = match entry.header {
+                    Header::OfsDelta { base_distance } => {
+                        let base_index = offset
+                            .checked_sub(base_distance)
+                            .and_then(|base| offset_indices.get(&base))
+                            .with_context(|| {
+                                format!("bad delta base for object {id}")
+                            })?;
+                        output::entry::Kind::DeltaRef {
+                            object_index: *base_index,
+                        }
+                    }
+                    Header::RefDelta { base_id } => {
+                        match id_locations.get(&base_id) {
+                            Some(&(_, base_index)) => {
+                                output::entry::Kind::DeltaRef {
+                                    object_index: base_index,
+                                }
+                            }
+                            None => {
+                                output::entry::Kind::DeltaOid { id: base_id }
+                            }
+                        }
+                    }
+                    header => output::entry::Kind::Base(
+                        header.as_kind().context("bad pack entry")?,
+                    ),
+                };
+                id_locations.insert(id, (offset, index));
+                offset_indices.insert(offset, index);
+                Ok(vec![output::Entry {
+                    id,
+                    kind,
+                    decompressed_size: usize::try_from(
+                        entry.decompressed_size,
+                    )?,
+                    compressed_data: entry.compressed.unwrap_or_default(),
+                }])
+            },
+        );
+        let buf = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let converted = output::bytes::FromEntriesIter::new(
+            entries.map(|res| res.map_err(io::Error::other)),
+            SharedWriter(buf.clone()),
+            pack.num_objects(),
+            gix_pack::data::Version::V2,
+            repo.object_hash(),
+        );
+        write_bundle(
+            repo,
+            &mut io::BufReader::new(PackBytesReader {
+                iter: converted,
+                buf,
+                pos: 0,
+            }),
+            pack_dir_path,
+        )
     }
-}
-
-fn repo_id_new() -> BString {
-    BString::from(uuid::Uuid::new_v4().to_string())
-}
 
-fn repo_id_read(repo: &git2::Repository) -> Option<BString> {
-    fs::read_to_string(&repo_id_path(repo))
-        .ok()
-        .map(|s| BString::from(s.trim_end()))
-}
+    // Appends written bytes to a buffer shared with a `PackBytesReader`.
+    struct SharedWriter(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
 
-fn repo_id_write(repo: &git2::Repository, repo_id: &BStr) -> AResult<()> {
-    fs::create_dir_all(&repo_state_root_path(repo))?;
-    let mut id_bytes = BString::from(repo_id);
-    id_bytes.push(b'\n');
-    fs::write(&repo_id_path(repo), id_bytes)?;
-    Ok(())
-}
+    impl io::Write for SharedWriter {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
 
-fn repo_meta_current(repo: &git2::Repository) -> AResult<RepoMeta> {
-    let mut meta = RepoMeta::new();
-    meta.orefs = repo_orefs(&repo)?;
-    let head_ref = repo
-        .find_reference("HEAD")
-        .context("cannot find `HEAD` reference")?;
-    meta.head_detached = repo
-        .head_detached()
-        .context("failed to determine detached state")?;
-    if meta.head_detached {
-        let head_commit_id = head_ref.target().ok_or(anyhow!(
-            "cannot retrieve detached-head commit id for `HEAD`"
-        ))?;
-        meta.head_ref = oid_to_bstring(&head_commit_id);
-    } else {
-        let head_sym_target = head_ref
-            .symbolic_target_bytes()
-            .ok_or(anyhow!("cannot retrieve symbolic ref for `HEAD`"))?;
-        meta.head_ref = BString::from(head_sym_target);
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
-    if let Ok(head_commit) = head_ref.peel_to_commit() {
-        meta.orefs.insert(BString::from("HEAD"), head_commit.id());
+
+    // Reads the pack bytes that `iter` writes into `buf`, advancing `iter`
+    // only as they are consumed.
+    struct PackBytesReader<I> {
+        iter: I,
+        buf: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+        pos: usize,
     }
-    for (_name, &oid) in meta.orefs.iter() {
-        // It's possible to have an `oref` that does not peel to a commit;
-        // for example, in the Linux kernel source tree, the tag `v2.6.11`
-        // refers to a tag object (5dc01c595e6c6ec9ccda4f6f69c131c0dd945f8c)
-        // which points directly to a tree object
-        // (5dc01c595e6c6ec9ccda4f6f69c131c0dd945f8c).  This is unusual but
-        // legal; for the tag `v2.6.11`, it was done because Git history does
-        // not exist for earlier kernel versions.  The tagged tree object
-        // provides a hook for future importation of this older source, and an
-        // anchor point for doing diffs.
-        //
-        // If the `oref` does not have an associated commit, there's nothing to
-        // track in `meta.commits`.
-        if let Ok((commit_id, comment)) = repo_commit_id_comment(repo, oid) {
-            meta.commits.insert(commit_id, comment);
+
+    impl<I, E> io::Read for PackBytesReader<I>
+    where
+        I: Iterator<Item = Result<u64, E>>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            loop {
+                {
+                    let buf = self.buf.borrow();
+                    if self.pos < buf.len() {
+                        let len = out.len().min(buf.len() - self.pos);
+                        out[..len]
+                            .copy_from_slice(&buf[self.pos..self.pos + len]);
+                        self.pos += len;
+                        return Ok(len);
+                    }
+                }
+                self.buf.borrow_mut().clear();
+                self.pos = 0;
+                match self.iter.next() {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(io::Error::other(e)),
+                    None => return Ok(0),
+                }
+            }
+        }
+    }
+
+    // Writes the pack read from `pack_reader` and its index into
+    // `pack_dir_path`.
+    fn write_bundle(
+        repo: &gix::Repository,
+        pack_reader: &mut dyn io::BufRead,
+        pack_dir_path: &path::Path,
+    ) -> AResult<()> {
+        let outcome = gix_pack::Bundle::write_to_directory(
+            pack_reader,
+            Some(pack_dir_path),
+            &mut gix::progress::Discard,
+            &AtomicBool::new(false),
+            Some(repo.objects.clone()),
+            gix_pack::bundle::write::Options {
+                object_hash: repo.object_hash(),
+                ..Default::default()
+            },
+        )
+        .context("failure indexing ibundle pack")?;
+        // The pack needs no protection from `git gc`, as refs to its
+        // objects are set right away.
+        if let Some(keep_path) = outcome.keep_path {
+            fs::remove_file(keep_path)?;
+        }
+        Ok(())
+    }
+
+    impl Backend for GitoxideBackend {
+        fn name(&self) -> &'static str {
+            "gitoxide"
+        }
+
+        fn refs(&self, repo: &git2::Repository) -> AResult<ORefs> {
+            let grepo = open(repo)?;
+            let mut orefs = ORefs::new();
+            for r in grepo.references()?.all()? {
+                let r = r.map_err(|e| anyhow!(e))?;
+                let id = match r.target().try_id() {
+                    Some(id) => id.to_owned(),
+                    None => bail!(
+                        "found non-direct ref {}",
+                        quoted(r.name().as_bstr())
+                    ),
+                };
+                orefs.insert(RefName::from(r.name().as_bstr()), git2_oid(&id)?);
+            }
+            Ok(orefs)
+        }
+
+        fn has_object(&self, repo: &git2::Repository, oid: git2::Oid) -> bool {
+            open(repo).is_ok_and(|grepo| grepo.has_object(gix_oid(oid)))
+        }
+
+        fn plan_pack<'r>(
+            &self,
+            repo: &'r git2::Repository,
+            bundle_orefs: &ORefs,
+            excluded_oids: &collections::HashSet<&git2::Oid>,
+        ) -> AResult<PackPlan<'r>> {
+            let grepo = open(repo)?;
+            let hidden_ids = excluded_oids
+                .iter()
+                .filter_map(|&&oid| peel_commit(&grepo, gix_oid(oid)))
+                .collect::<Vec<_>>();
+            let tip_ids = bundle_orefs
+                .values()
+                .filter_map(|&oid| peel_commit(&grepo, gix_oid(oid)))
+                .collect::<Vec<_>>();
+            let mut new_commit_ids = Vec::new();
+            if !tip_ids.is_empty() {
+                for info in
+                    grepo.rev_walk(tip_ids).with_hidden(hidden_ids).all()?
+                {
+                    new_commit_ids.push(info?.id);
+                }
+            }
+            let new_commit_id_set =
+                new_commit_ids.iter().collect::<collections::HashSet<_>>();
+
+            // Boundary commits are prerequisites.
+            let mut prereqs = Commits::new();
+            for &commit_id in new_commit_ids.iter() {
+                for parent_id in grepo.find_commit(commit_id)?.parent_ids() {
+                    if !new_commit_id_set.contains(&parent_id.detach()) {
+                        add_prereq(repo, &mut prereqs, &parent_id)?;
+                    }
+                }
+            }
+
+            let mut packed_orefs = ORefs::new();
+            let mut tag_ids = Vec::new();
+            let mut target_ids = Vec::new();
+            for (name, &oid) in bundle_orefs.iter() {
+                let obj = grepo.find_object(gix_oid(oid))?;
+                let packed = if obj.kind == gix::object::Kind::Commit {
+                    new_commit_id_set.contains(&obj.id)
+                } else {
+                    !excluded_oids.contains(&oid)
+                };
+                if packed {
+                    packed_orefs.insert(name.clone(), oid);
+                    // Tags (and refs to trees or blobs) are outside the walk.
+                    let mut target = obj;
+                    while target.kind == gix::object::Kind::Tag {
+                        tag_ids.push(target.id);
+                        target =
+                            target.try_into_tag()?.target_id()?.object()?;
+                    }
+                    if target.kind != gix::object::Kind::Commit {
+                        target_ids.push((target.id, target.kind));
+                    }
+                }
+                // We want every oref, even those whose commit is excluded by
+                // the basis, so such commits become prerequisites.
+                if let Some(commit_id) = peel_commit(&grepo, gix_oid(oid)) {
+                    if !new_commit_id_set.contains(&commit_id) {
+                        add_prereq(repo, &mut prereqs, &commit_id)?;
+                    }
+                }
+            }
+
+            // Objects reachable from prerequisites are already at the
+            // destination.
+            let mut seen = collections::HashSet::new();
+            for &oid in prereqs.keys() {
+                let tree_id = grepo.find_commit(gix_oid(oid))?.tree_id()?;
+                tree_objects(&grepo, tree_id.detach(), &mut seen, None)?;
+            }
+            let mut ids = Vec::new();
+            for &commit_id in new_commit_ids.iter() {
+                ids.push(commit_id);
+                let tree_id = grepo.find_commit(commit_id)?.tree_id()?;
+                tree_objects(
+                    &grepo,
+                    tree_id.detach(),
+                    &mut seen,
+                    Some(&mut ids),
+                )?;
+            }
+            for tag_id in tag_ids {
+                if seen.insert(tag_id) {
+                    ids.push(tag_id);
+                }
+            }
+            for (id, kind) in target_ids {
+                if kind == gix::object::Kind::Tree {
+                    tree_objects(&grepo, id, &mut seen, Some(&mut ids))?;
+                } else if seen.insert(id) {
+                    ids.push(id);
+                }
+            }
+            Ok(PackPlan {
+                prereqs,
+                packed_orefs,
+                write: Box::new(move |writer| write_pack(&grepo, ids, writer)),
+            })
+        }
+
+        fn index_pack(
+            &self,
+            repo: &git2::Repository,
+            mut pack_file: fs::File,
+        ) -> AResult<()> {
+            let grepo = open(repo)?;
+            let pack_dir_path = repo.path().join("objects").join("pack");
+            fs::create_dir_all(&pack_dir_path)?;
+            let pack_offset = pack_file.stream_position()?;
+            let has_ref_deltas = has_ref_deltas(&pack_file, &grepo)?;
+            pack_file.seek(io::SeekFrom::Start(pack_offset))?;
+            if !has_ref_deltas {
+                return write_bundle(
+                    &grepo,
+                    &mut io::BufReader::new(pack_file),
+                    &pack_dir_path,
+                );
+            }
+            // Converting needs random access to a pack file of its own.
+            let spool_path = pack_dir_path.join(format!(
+                "tmp_ibundle_spool_{}.pack",
+                uuid::Uuid::new_v4()
+            ));
+            let _spool_path_deleter = FileDeleter::new(&spool_path);
+            let mut spool_writer = create_writer(&spool_path)?;
+            io::copy(&mut pack_file, &mut spool_writer)
+                .and_then(|_| spool_writer.flush())
+                .context("failure indexing ibundle pack")?;
+            drop(spool_writer);
+            index_converted_pack(&grepo, &spool_path, &pack_dir_path)
+        }
+
+        fn update_refs(
+            &self,
+            repo: &git2::Repository,
+            updates: &[(RefName, Option<git2::Oid>)],
+            log_message: &str,
+        ) -> AResult<()> {
+            use gix::refs::transaction::{
+                Change, LogChange, PreviousValue, RefEdit, RefLog,
+            };
+            let mut grepo = open(repo)?;
+            let committer =
+                grepo.committer_or_set_generic_fallback()?.to_owned()?;
+            let mut edits = Vec::new();
+            for (name, oid) in updates.iter() {
+                let name = gix::refs::FullName::try_from(name.as_bstr())
+                    .with_context(|| format!("invalid ref {}", quoted(name)))?;
+                let change = match oid {
+                    Some(oid) => Change::Update {
+                        log: LogChange {
+                            mode: RefLog::AndReference,
+                            force_create_reflog: false,
+                            message: log_message.into(),
+                        },
+                        expected: PreviousValue::Any,
+                        new: gix::refs::Target::Object(gix_oid(*oid)),
+                    },
+                    None if grepo.try_find_reference(&name)?.is_none() => {
+                        continue
+                    }
+                    None => Change::Delete {
+                        expected: PreviousValue::Any,
+                        log: RefLog::AndReference,
+                    },
+                };
+                edits.push(RefEdit {
+                    change,
+                    name,
+                    deref: false,
+                });
+            }
+            // One transaction, so that all refs change or none do.
+            grepo.edit_references_as(
+                edits,
+                Some(committer.to_ref(&mut Default::default())),
+            )?;
+            Ok(())
         }
     }
-    Ok(meta)
 }
 
-fn repo_meta_read(
+//////////////////////////////////////////////////////////////////////////////
+
+// Moves the packs of quarantine repository `quarantine_path` into `repo`.
+fn quarantine_migrate_packs(
     repo: &git2::Repository,
-    seq_num: SeqNum,
-) -> AResult<RepoMeta> {
-    let meta_path = repo_meta_path(repo, seq_num);
-    let mut f = open_reader(&meta_path)?;
-    RepoMeta::read(&mut f)
+    quarantine_path: &path::Path,
+) -> AResult<()> {
+    let pack_dir_path = repo.path().join("objects").join("pack");
+    fs::create_dir_all(&pack_dir_path)?;
+    let mut paths = fs::read_dir(quarantine_path.join("objects").join("pack"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<AResult<Vec<_>>>()?;
+    // Move each `.idx` after its `.pack`, since the index announces the pack.
+    paths.sort_by_key(|path| path.extension() == Some(ffi::OsStr::new("idx")));
+    for path in paths {
+        let new_path = pack_dir_path.join(path.file_name().unwrap_or_default());
+        if fs::rename(&path, &new_path).is_err() {
+            // The state directory may be on another filesystem.
+            fs::copy(&path, &new_path).with_context(|| {
+                format!("failed to copy {}", quoted_path(&path))
+            })?;
+        }
+    }
+    Ok(())
 }
 
-fn repo_meta_write(
-    repo: &git2::Repository,
-    seq_num: SeqNum,
-    meta: &RepoMeta,
+fn git_unpack_objects(
+    git_dir_path: &path::Path,
+    pack_file: fs::File,
 ) -> AResult<()> {
-    let meta_dir_path = repo_meta_dir_path(&repo);
-    fs::create_dir_all(&meta_dir_path)?;
-    let meta_path = repo_meta_path(repo, seq_num);
-    let mut f = create_writer(&meta_path)?;
-    meta.write(&mut f)
+    let exit_status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(git_dir_path)
+        .args(["unpack-objects", "-q"])
+        .stdin(pack_file)
+        .status()?;
+    if !exit_status.success() {
+        bail!("failure in git unpack-objects");
+    }
+    Ok(())
+}
+
+// Returns one line per problem reported by `git fsck`.
+fn git_fsck_loose_objects(git_dir_path: &path::Path) -> AResult<Vec<BString>> {
+    // `--no-full` restricts checking to the loose objects in `git_dir_path`,
+    // skipping objects found via alternates.
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(git_dir_path)
+        .args([
+            "fsck",
+            "--strict",
+            "--no-full",
+            "--no-dangling",
+            "--no-reflogs",
+            "--no-progress",
+        ])
+        .output()?;
+    let problems = ByteSlice::lines(output.stdout.as_slice())
+        .chain(ByteSlice::lines(output.stderr.as_slice()))
+        .filter(|line| !line.is_empty() && !line.starts_with(b"notice:"))
+        .map(BString::from)
+        .collect::<Vec<_>>();
+    if !output.status.success() && problems.is_empty() {
+        bail!("failure in git fsck");
+    }
+    Ok(problems)
 }
 
 //////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone)]
-struct IBundle {
-    repo_id: BString,
-    seq_num: SeqNum,
-    basis_seq_num: SeqNum,
-    head_ref: BString,
-    head_detached: bool,
-    prereqs: Commits,
-    added_orefs: ORefs,
-    removed_orefs: ORefs,
-    moved_orefs: ORefs,
-    unchanged_orefs: Option<ORefs>,
-    packed_orefs: ORefs,
+// Indexes `pack_file` into a temporary quarantine repository `name` whose
+// alternates point at `repo`, so thin-pack deltas resolve against existing
+// objects while nothing is written into `repo` itself.  The quarantine
+// repository is removed when the returned `DirDeleter` is dropped.
+fn repo_quarantine_pack(
+    repo: &git2::Repository,
+    name: &str,
+    pack_file: fs::File,
+) -> AResult<(path::PathBuf, DirDeleter)> {
+    let quarantine_path = repo_mktemp(repo)?.join(name);
+    if quarantine_path.exists() {
+        fs::remove_dir_all(&quarantine_path)?;
+    }
+    let quarantine_path_deleter = DirDeleter::new(&quarantine_path);
+    git2::Repository::init_bare(&quarantine_path)?;
+    let objects_path = fs::canonicalize(repo.path().join("objects"))?;
+    let mut alternates = Vec::from_path_lossy(&objects_path).into_owned();
+    alternates.push(b'\n');
+    fs::write(
+        quarantine_path
+            .join("objects")
+            .join("info")
+            .join("alternates"),
+        alternates,
+    )?;
+    backend().index_pack(&repo_open(&quarantine_path)?, pack_file)?;
+    Ok((quarantine_path, quarantine_path_deleter))
 }
 
-impl IBundle {
-    fn construct(
-        repo_id: BString,
-        seq_num: SeqNum,
-        basis_seq_num: SeqNum,
-        meta: &RepoMeta,
-        basis_meta: &RepoMeta,
-    ) -> AResult<Self> {
-        let mut removed_orefs = ORefs::new();
-        for (name, &oid) in basis_meta.orefs.iter() {
-            if !meta.orefs.contains_key(name) {
-                removed_orefs.insert(name.clone(), oid);
+// Checks every object in the quarantined pack via `git fsck --strict`.  Only
+// the newly arriving objects (unpacked as loose objects into a scratch
+// repository sharing the quarantine's alternates) are checked.
+fn quarantine_fsck_objects(quarantine_path: &path::Path) -> AResult<()> {
+    let fsck_path = quarantine_path.join("fsck.git");
+    git2::Repository::init_bare(&fsck_path)?;
+    let alternates_path =
+        path::Path::new("objects").join("info").join("alternates");
+    fs::copy(
+        quarantine_path.join(&alternates_path),
+        fsck_path.join(&alternates_path),
+    )?;
+    let pack_dir_path = quarantine_path.join("objects").join("pack");
+    for entry in fs::read_dir(&pack_dir_path)? {
+        let path = entry?.path();
+        if path.extension() == Some(ffi::OsStr::new("pack")) {
+            git_unpack_objects(&fsck_path, open_file(&path)?)?;
+        }
+    }
+    let problems = git_fsck_loose_objects(&fsck_path)?;
+    if !problems.is_empty() {
+        if log_enabled!(Level::Error) {
+            eprintln!("ibundle pack has {} object problems:", problems.len());
+            for problem in problems.iter() {
+                eprintln!("  {}", problem);
             }
         }
+        bail!("ibundle pack failed object checks; refusing to fetch");
+    }
+    log::info!("ibundle pack passed object checks");
+    Ok(())
+}
 
-        let mut added_orefs = ORefs::new();
-        let mut moved_orefs = ORefs::new();
-        let mut unchanged_orefs = ORefs::new();
-        for (name, &oid) in meta.orefs.iter() {
-            let name = name.clone();
-            if let Some(&oid2) = basis_meta.orefs.get(&name) {
-                if oid == oid2 {
-                    unchanged_orefs.insert(name, oid);
-                } else {
-                    moved_orefs.insert(name, oid);
+// Checks applied to the ibundle's pack before fetching.
+struct PackChecks<'a> {
+    // History reachable from these refs is already accepted.
+    old_orefs: &'a ORefs,
+
+    // All refs after fetching; those of `old_orefs` missing here are removed.
+    new_orefs: &'a ORefs,
+
+    // Commits the ibundle requires; their history is also accepted.
+    prereqs: &'a Commits,
+
+    fsck_objects: bool,
+
+    verify_signatures: bool,
+
+    // Overrides Git's `gpg.ssh.allowedSignersFile`.
+    allowed_signers_path: Option<path::PathBuf>,
+
+    identity_policy: Option<IdentityPolicy>,
+
+    max_forced_refs: Option<u64>,
+
+    // Patterns for refs that may only move fast-forward.
+    fast_forward_only: Vec<BString>,
+}
+
+impl PackChecks<'_> {
+    fn any(&self) -> bool {
+        self.fsck_objects
+            || self.verify_signatures
+            || self.identity_policy.is_some()
+            || self.max_forced_refs.is_some()
+            || !self.fast_forward_only.is_empty()
+    }
+}
+
+// A `git` command within `repo`, with `quarantine_path` objects available
+// as alternates.
+fn git_quarantine_command(
+    repo: &git2::Repository,
+    quarantine_path: &path::Path,
+    allowed_signers_path: Option<&path::Path>,
+) -> std::process::Command {
+    let mut cmd = std::process::Command::new("git");
+    if let Some(allowed_signers_path) = allowed_signers_path {
+        let mut setting = ffi::OsString::from("gpg.ssh.allowedSignersFile=");
+        setting.push(allowed_signers_path);
+        cmd.arg("-c").arg(setting);
+    }
+    cmd.arg("-C").arg(repo.path()).env(
+        "GIT_ALTERNATE_OBJECT_DIRECTORIES",
+        quarantine_path.join("objects"),
+    );
+    cmd
+}
+
+// Runs `git verify-commit` or `git verify-tag` (per `verify_cmd`) on `oid`.
+fn git_verify_signature(
+    repo: &git2::Repository,
+    quarantine_path: &path::Path,
+    allowed_signers_path: Option<&path::Path>,
+    verify_cmd: &str,
+    oid: git2::Oid,
+) -> AResult<bool> {
+    let exit_status =
+        git_quarantine_command(repo, quarantine_path, allowed_signers_path)
+            .arg(verify_cmd)
+            .arg(oid.to_string())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .with_context(|| format!("failed to run git {}", verify_cmd))?;
+    Ok(exit_status.success())
+}
+
+// Trust levels as named by `git log --format=%GT` and `gpg.minTrustLevel`,
+// lowest first.
+const GPG_TRUST_LEVELS: &[&str] =
+    &["undefined", "never", "marginal", "fully", "ultimate"];
+
+fn gpg_trust_level(name: &[u8]) -> usize {
+    GPG_TRUST_LEVELS
+        .iter()
+        .position(|level| level.as_bytes() == name)
+        .unwrap_or(0)
+}
+
+// Returns those of `commit_ids` lacking a signature that `git verify-commit`
+// would accept, checking all of them with a single `git log`.  A good
+// signature (`%G?` of `G`) must also meet `gpg.minTrustLevel`.  `U` (good,
+// unknown validity) is accepted by `git verify-commit` for GPG signatures
+// but not for SSH signers missing from the allowed-signers file, so those
+// commits alone are rechecked individually.
+fn git_unverified_commits(
+    repo: &git2::Repository,
+    quarantine_path: &path::Path,
+    allowed_signers_path: Option<&path::Path>,
+    commit_ids: &[git2::Oid],
+) -> AResult<collections::HashSet<git2::Oid>> {
+    if commit_ids.is_empty() {
+        return Ok(collections::HashSet::new());
+    }
+    let min_trust_level = repo_config_string(repo, "gpg.minTrustLevel")?
+        .map(|name| gpg_trust_level(name.to_ascii_lowercase().as_bytes()))
+        .unwrap_or(0);
+    let mut child =
+        git_quarantine_command(repo, quarantine_path, allowed_signers_path)
+            .args(["log", "--no-walk=unsorted", "--stdin"])
+            .arg("--format=%H %G? %GT")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .context("failed to run git log")?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let mut ids = Vec::new();
+    for commit_id in commit_ids.iter() {
+        write_oid(&mut ids, commit_id)?;
+        ids.push(b'\n');
+    }
+    // Feed commit IDs while reading results, lest both pipes fill.
+    let writer = std::thread::spawn(move || stdin.write_all(&ids));
+    let output = child.wait_with_output()?;
+    writer.join().expect("stdin writer panicked")?;
+    if !output.status.success() {
+        bail!("failure in git log checking commit signatures");
+    }
+    let mut verified = collections::HashSet::new();
+    for line in ByteSlice::lines(output.stdout.as_slice()) {
+        let mut fields = line.splitn_str(3, " ");
+        let (oid, result, trust) =
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(oid), Some(result), trust) => {
+                    (oid, result, trust.unwrap_or_default())
                 }
-            } else {
-                added_orefs.insert(name, oid);
+                _ => continue,
+            };
+        let oid = parse_oid(oid.as_bstr())?;
+        let is_verified = match result {
+            b"G" => gpg_trust_level(trust) >= min_trust_level,
+            b"U" => git_verify_signature(
+                repo,
+                quarantine_path,
+                allowed_signers_path,
+                "verify-commit",
+                oid,
+            )?,
+            _ => false,
+        };
+        if is_verified {
+            verified.insert(oid);
+        }
+    }
+    Ok(commit_ids
+        .iter()
+        .filter(|commit_id| !verified.contains(*commit_id))
+        .copied()
+        .collect())
+}
+
+// Verifies signatures on all commits and tag objects that `bundle_orefs`
+// would newly introduce (i.e., those not reachable from
+// `checks.old_orefs`), reporting each unsigned or untrusted object.
+fn repo_verify_signatures(
+    repo: &git2::Repository,
+    quarantine_path: &path::Path,
+    bundle_orefs: &ORefs,
+    checks: &PackChecks,
+) -> AResult<()> {
+    let quarantine = repo_open(quarantine_path)?;
+    let allowed_signers_path = checks.allowed_signers_path.as_deref();
+    let mut problems = Vec::new();
+
+    let mut tag_ids = collections::BTreeSet::new();
+    for &oid in bundle_orefs.values() {
+        let mut oid = oid;
+        while !repo_has_oid(repo, oid) {
+            match quarantine.find_tag(oid) {
+                Ok(tag) if tag_ids.insert(oid) => oid = tag.target_id(),
+                _ => break,
             }
         }
+    }
+    for &tag_id in tag_ids.iter() {
+        if !git_verify_signature(
+            repo,
+            quarantine_path,
+            allowed_signers_path,
+            "verify-tag",
+            tag_id,
+        )? {
+            let tag = quarantine.find_tag(tag_id)?;
+            problems.push(format!(
+                "tag {} {}",
+                tag_id,
+                quoted(tag.name_bytes())
+            ));
+        }
+    }
 
-        let ibundle = IBundle {
-            repo_id,
-            seq_num,
-            basis_seq_num,
-            head_ref: meta.head_ref.clone(),
-            head_detached: meta.head_detached,
-            prereqs: Commits::new(),
-            added_orefs: added_orefs,
-            removed_orefs: removed_orefs,
-            moved_orefs,
-            unchanged_orefs: Some(unchanged_orefs),
-            packed_orefs: ORefs::new(),
-        };
+    let commit_ids =
+        repo_new_commits(&quarantine, bundle_orefs, checks.old_orefs.values())?;
+    let unverified_commit_ids = git_unverified_commits(
+        repo,
+        quarantine_path,
+        allowed_signers_path,
+        &commit_ids,
+    )?;
+    for &commit_id in commit_ids.iter() {
+        if unverified_commit_ids.contains(&commit_id) {
+            let commit = quarantine.find_commit(commit_id)?;
+            problems.push(format!(
+                "commit {} {}",
+                commit_id,
+                quoted(commit.summary_bytes().unwrap_or_default())
+            ));
+        }
+    }
 
-        Ok(ibundle)
+    if !problems.is_empty() {
+        if log_enabled!(Level::Error) {
+            eprintln!(
+                "ibundle has {} unsigned or untrusted objects:",
+                problems.len()
+            );
+            for problem in problems.iter() {
+                eprintln!("  {}", problem);
+            }
+        }
+        bail!("ibundle history failed signature checks; refusing to fetch");
     }
+    log::info!(
+        "verified signatures on {} commits and {} tags",
+        commit_ids.len(),
+        tag_ids.len()
+    );
+    Ok(())
+}
 
-    fn new() -> Self {
-        Self {
-            repo_id: BString::from(""),
-            seq_num: 0,
-            basis_seq_num: 0,
-            head_ref: BString::from(""),
-            head_detached: false,
-            prereqs: Commits::new(),
-            added_orefs: ORefs::new(),
-            removed_orefs: ORefs::new(),
-            moved_orefs: ORefs::new(),
-            unchanged_orefs: None,
-            packed_orefs: ORefs::new(),
+// Stores the objects from `pack_file` in `repo`, ready for updating
+// `bundle_orefs` (left to the caller, so that nothing changes any refs until
+// every check has passed).  With checks (or for a dry run), the pack is first
+// indexed into a quarantine repository and only moved into `repo` once it
+// passes.
+fn repo_fetch(
+    repo: &git2::Repository,
+    bundle_orefs: &ORefs,
+    pack_file: fs::File,
+    dry_run: bool,
+    checks: &PackChecks,
+) -> AResult<()> {
+    if !checks.any() && !dry_run {
+        backend().index_pack(repo, pack_file)?;
+    } else {
+        let (quarantine_path, quarantine_path_deleter) =
+            repo_quarantine_pack(repo, "check.git", pack_file)?;
+        if checks.fsck_objects {
+            quarantine_fsck_objects(&quarantine_path)?;
+        }
+        if checks.verify_signatures {
+            repo_verify_signatures(
+                repo,
+                &quarantine_path,
+                bundle_orefs,
+                checks,
+            )?;
+        }
+        if checks.max_forced_refs.is_some()
+            || !checks.fast_forward_only.is_empty()
+        {
+            let quarantine = repo_open(&quarantine_path)?;
+            let forced =
+                forced_refs(&quarantine, checks.old_orefs, bundle_orefs);
+            if let Some(max_forced_refs) = checks.max_forced_refs {
+                if let Some(violation) =
+                    forced_refs_violation(forced.len(), max_forced_refs)
+                {
+                    bail!(StatusError::new(
+                        STATUS_MASS_CHANGE,
+                        format!(
+                            "ibundle {}; refusing to fetch \
+                            (consider `--allow-mass-changes`)",
+                            violation
+                        )
+                    ));
+                }
+            }
+            let removed = checks
+                .old_orefs
+                .keys()
+                .filter(|name| {
+                    *name != b"HEAD".as_bstr()
+                        && !checks.new_orefs.contains_key(*name)
+                })
+                .collect::<Vec<_>>();
+            check_fast_forward_only(
+                &forced,
+                &removed,
+                &checks.fast_forward_only,
+                checks.old_orefs,
+                bundle_orefs,
+            )?;
         }
+        if let Some(identity_policy) = &checks.identity_policy {
+            quarantine_check_identities(
+                &quarantine_path,
+                identity_policy,
+                bundle_orefs,
+                checks.old_orefs,
+            )?;
+        }
+        if dry_run {
+            // Objects and refs must resolve just as for a real fetch.
+            let quarantine = repo_open(&quarantine_path)?;
+            repo_check_connected(&quarantine, bundle_orefs, checks)?;
+            return Ok(());
+        }
+        quarantine_migrate_packs(repo, &quarantine_path)?;
+        drop(quarantine_path_deleter);
     }
 
-    fn read<R: io::BufRead>(reader: &mut R) -> AResult<Self> {
-        let mut bline = BString::from("");
-        read_bline(reader, &mut bline)?;
-        if bline != IBUNDLE_FORMAT_V2 {
-            bail!("not a V2 ibundle file");
+    repo_check_connected(repo, bundle_orefs, checks)
+}
+
+// Walks every object reachable from `orefs`, stopping at history already
+// accepted (per `checks`), and fails on the first object that `repo` lacks.
+// Refs are only updated after this passes, so they never point at incomplete
+// history.
+fn repo_check_connected(
+    repo: &git2::Repository,
+    orefs: &ORefs,
+    checks: &PackChecks,
+) -> AResult<()> {
+    let accepted_oids = checks
+        .old_orefs
+        .values()
+        .chain(checks.prereqs.keys())
+        .collect::<Vec<_>>();
+    let mut seen_tree_ids = collections::HashSet::new();
+    for &oid in accepted_oids.iter() {
+        if let Ok(commit) = repo_commit(repo, *oid) {
+            seen_tree_ids.insert(commit.tree_id());
         }
+    }
 
-        let mut ibundle = Self::new();
-        let mut added_packed_orefs = ORefs::new();
-        let mut added_not_packed_orefs = ORefs::new();
-        let mut moved_packed_orefs = ORefs::new();
-        let mut moved_not_packed_orefs = ORefs::new();
-        while read_bline(reader, &mut bline)? > 0 {
-            if bline.starts_with(b"%") {
-                let (dir, rest) = bstr_pop_word(bline[1..].as_bstr());
-                if dir == Directive::REPO_ID {
-                    ibundle.repo_id = BString::from(rest);
-                } else if dir == Directive::SEQ_NUM {
-                    ibundle.seq_num = parse_seq_num(rest)?;
+    for (name, &oid) in orefs.iter() {
+        let mut oid = oid;
+        loop {
+            if !repo_has_oid(repo, oid) {
+                bail!("ibundle pack lacks object {} for {}", oid, quoted(name));
+            }
+            let object = repo.find_object(oid, None)?;
+            match object.kind() {
+                Some(git2::ObjectType::Tag) => {
+                    oid = repo.find_tag(oid)?.target_id();
+                }
+                Some(git2::ObjectType::Tree) => {
+                    repo_check_tree_connected(repo, oid, &mut seen_tree_ids)?;
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    let commit_ids = repo_new_commits(repo, orefs, accepted_oids)
+        .context("ibundle pack lacks history")?;
+    for commit_id in commit_ids {
+        let tree_id = repo.find_commit(commit_id)?.tree_id();
+        repo_check_tree_connected(repo, tree_id, &mut seen_tree_ids)?;
+    }
+    Ok(())
+}
+
+fn repo_check_tree_connected(
+    repo: &git2::Repository,
+    tree_id: git2::Oid,
+    seen_tree_ids: &mut collections::HashSet<git2::Oid>,
+) -> AResult<()> {
+    let mut tree_ids = vec![tree_id];
+    while let Some(tree_id) = tree_ids.pop() {
+        if !seen_tree_ids.insert(tree_id) {
+            continue;
+        }
+        let tree = match repo.find_tree(tree_id) {
+            Ok(tree) => tree,
+            Err(_) => bail!("ibundle pack lacks tree {}", tree_id),
+        };
+        for entry in tree.iter() {
+            match entry.kind() {
+                Some(git2::ObjectType::Tree) => tree_ids.push(entry.id()),
+                Some(git2::ObjectType::Blob)
+                    if !repo_has_oid(repo, entry.id()) =>
+                {
+                    bail!("ibundle pack lacks blob {}", entry.id());
+                }
+                // Blobs present, and submodule commits (which belong to other
+                // repositories).
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// git2::Repository::set_head() is below:
+//
+//   pub fn set_head(&self, refname: &str) -> Result<(), Error> {
+//       let refname = CString::new(refname)?;
+//       unsafe {
+//           try_call!(raw::git_repository_set_head(self.raw, refname));
+//       }
+//       Ok(())
+//   }
+//
+// The passed-in `&str` value `refname` is not interpreted in any way; it's
+// simply passed along into `git_repository_set_head()`, a function that expects
+// raw bytes and does not require UTF8 semantics.
+//
+// Until git2 provides a `set_head_bytes()` function as requested in
+// <https://github.com/rust-lang/git2-rs/issues/925>, and provided in pull
+// request <https://github.com/rust-lang/git2-rs/pull/931>, the only way to
+// support non-utf8 head values with git2 is to use the unsafe conversion
+// `String::from_utf8_unchecked()`.
+
+fn repo_set_head_ref(
+    repo: &git2::Repository,
+    head_ref: impl AsRef<BStr>,
+) -> AResult<()> {
+    let head_ref = head_ref.as_ref();
+    if let Ok(head_ref_str) = name_to_string(head_ref) {
+        repo.set_head(&head_ref_str)?;
+    } else {
+        // `head_ref` is non-utf8.
+        let head_ref_bytes = head_ref.to_vec();
+        // Safety: repo.set_head() does not interpret its argument as a
+        // utf8-string; it merely passed it along to the underlying library
+        // that's expecting raw bytes.
+        repo.set_head(&unsafe { String::from_utf8_unchecked(head_ref_bytes) })?;
+    }
+    Ok(())
+}
+
+// As with `repo_set_head_ref()`, `repo.reference()` passes `name` along as
+// raw bytes, so non-utf8 names are supported via the same unsafe conversion.
+// Returns ref `name` for passing to git2.
+fn ref_name_str(name: &BStr) -> String {
+    if let Ok(name_str) = name_to_string(name) {
+        name_str
+    } else {
+        // Safety: git2 does not interpret ref names as utf8-strings; it
+        // merely passes them along to the underlying library that's
+        // expecting raw bytes.
+        unsafe { String::from_utf8_unchecked(name.to_vec()) }
+    }
+}
+
+fn repo_has_oid(repo: &git2::Repository, oid: git2::Oid) -> bool {
+    backend().has_object(repo, oid)
+}
+
+fn repo_commit(
+    repo: &git2::Repository,
+    commit_id: git2::Oid,
+) -> AResult<git2::Commit<'_>> {
+    Ok(repo.find_object(commit_id, None)?.peel_to_commit()?)
+}
+
+fn commit_comment(commit: &git2::Commit) -> BString {
+    let comment = if let Some(summary) = commit.summary_bytes() {
+        BString::from(summary)
+    } else {
+        BString::from("")
+    };
+    comment
+}
+
+fn repo_commit_id_comment(
+    repo: &git2::Repository,
+    commit_id: git2::Oid,
+) -> AResult<(git2::Oid, BString)> {
+    let commit = repo_commit(repo, commit_id)?;
+    Ok((commit.id(), commit_comment(&commit)))
+}
+
+fn repo_seq_nums(repo: &git2::Repository) -> AResult<SeqNums> {
+    let mut seq_nums = SeqNums::new();
+    let meta_dir_path = repo_meta_dir_path(repo)?;
+    if let Ok(dir_iter) = fs::read_dir(&meta_dir_path) {
+        for entry in dir_iter {
+            if let Ok(seq_num) =
+                entry?.file_name().to_string_lossy().parse::<u64>()
+            {
+                seq_nums.push(seq_num);
+            }
+        }
+    }
+    seq_nums.sort_by(|a, b| b.cmp(a));
+    Ok(seq_nums)
+}
+
+fn repo_has_basis(repo: &git2::Repository, basis_seq_num: &SeqNum) -> bool {
+    if let Ok(seq_nums) = repo_seq_nums(repo) {
+        seq_nums.contains(basis_seq_num)
+    } else {
+        false
+    }
+}
+
+fn repo_id_new() -> BString {
+    BString::from(uuid::Uuid::new_v4().to_string())
+}
+
+fn repo_id_read(repo: &git2::Repository) -> Option<BString> {
+    fs::read_to_string(repo_id_path(repo).ok()?)
+        .ok()
+        .map(|s| BString::from(s.trim_end()))
+}
+
+fn repo_id_write(repo: &git2::Repository, repo_id: &BStr) -> AResult<()> {
+    fs::create_dir_all(repo_state_root_path(repo)?)?;
+    let mut id_bytes = BString::from(repo_id);
+    id_bytes.push(b'\n');
+    fs::write(repo_id_path(repo)?, id_bytes)?;
+    Ok(())
+}
+
+// Ref name globs selecting the refs an ibundle mirrors: those matching any
+// `include` pattern (or all, without any) and no `exclude` pattern.
+#[derive(Debug, Clone, Default)]
+struct RefFilter {
+    include: Vec<BString>,
+    exclude: Vec<BString>,
+}
+
+impl RefFilter {
+    fn new(include: &[String], exclude: &[String]) -> Self {
+        Self {
+            include: include
+                .iter()
+                .map(|p| BString::from(p.as_str()))
+                .collect(),
+            exclude: exclude
+                .iter()
+                .map(|p| BString::from(p.as_str()))
+                .collect(),
+        }
+    }
+
+    fn keeps(&self, name: &BStr) -> bool {
+        (self.include.is_empty()
+            || self.include.iter().any(|pattern| glob_match(pattern, name)))
+            && !self.exclude.iter().any(|pattern| glob_match(pattern, name))
+    }
+}
+
+fn repo_meta_current(repo: &git2::Repository) -> AResult<RepoMeta> {
+    repo_meta_current_filtered(repo, &RefFilter::default())
+}
+
+fn repo_meta_current_filtered(
+    repo: &git2::Repository,
+    ref_filter: &RefFilter,
+) -> AResult<RepoMeta> {
+    let mut meta = RepoMeta::new();
+    meta.orefs = repo_orefs(repo)?;
+    let head_ref = repo
+        .find_reference("HEAD")
+        .context("cannot find `HEAD` reference")?;
+    meta.head_detached = repo
+        .head_detached()
+        .context("failed to determine detached state")?;
+    if meta.head_detached {
+        let head_commit_id = head_ref.target().ok_or(anyhow!(
+            "cannot retrieve detached-head commit id for `HEAD`"
+        ))?;
+        meta.head_ref = oid_to_bstring(&head_commit_id);
+    } else {
+        let head_sym_target = head_ref
+            .symbolic_target_bytes()
+            .ok_or(anyhow!("cannot retrieve symbolic ref for `HEAD`"))?;
+        meta.head_ref = BString::from(head_sym_target);
+        if meta.orefs.contains_key(&meta.head_ref)
+            && !ref_filter.keeps(meta.head_ref.as_bstr())
+        {
+            bail!(
+                "`HEAD` refers to filtered-out ref {}",
+                quoted(&meta.head_ref)
+            );
+        }
+    }
+    meta.orefs
+        .retain(|name, _| ref_filter.keeps(name.as_bstr()));
+    if let Ok(head_commit) = head_ref.peel_to_commit() {
+        meta.orefs.insert(BString::from("HEAD"), head_commit.id());
+    }
+    for (_name, &oid) in meta.orefs.iter() {
+        // It's possible to have an `oref` that does not peel to a commit;
+        // for example, in the Linux kernel source tree, the tag `v2.6.11`
+        // refers to a tag object (5dc01c595e6c6ec9ccda4f6f69c131c0dd945f8c)
+        // which points directly to a tree object
+        // (5dc01c595e6c6ec9ccda4f6f69c131c0dd945f8c).  This is unusual but
+        // legal; for the tag `v2.6.11`, it was done because Git history does
+        // not exist for earlier kernel versions.  The tagged tree object
+        // provides a hook for future importation of this older source, and an
+        // anchor point for doing diffs.
+        //
+        // If the `oref` does not have an associated commit, there's nothing to
+        // track in `meta.commits`.
+        if let Ok((commit_id, comment)) = repo_commit_id_comment(repo, oid) {
+            meta.commits.insert(commit_id, comment);
+        }
+    }
+    Ok(meta)
+}
+
+fn repo_hook_path(
+    repo: &git2::Repository,
+    hook_name: &str,
+) -> AResult<path::PathBuf> {
+    Ok(repo_state_root_path(repo)?.join("hooks").join(hook_name))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|m| m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &path::Path) -> bool {
+    true
+}
+
+// Runs hook `hook_name` (if present) with `env` and `stdin_bytes` on stdin.
+// The hook's stdout goes to stderr so it can't corrupt git-ibundle's own
+// output.  Returns `None` if the hook does not exist.
+fn repo_run_hook(
+    repo: &git2::Repository,
+    hook_name: &str,
+    env: &[(&str, ffi::OsString)],
+    stdin_bytes: &[u8],
+) -> AResult<Option<std::process::ExitStatus>> {
+    let hook_path = repo_hook_path(repo, hook_name)?;
+    if !hook_path.is_file() {
+        return Ok(None);
+    }
+    if !is_executable(&hook_path) {
+        log::warn!(
+            "ignoring hook {} because it is not executable",
+            quoted_path(&hook_path)
+        );
+        return Ok(None);
+    }
+    log::debug!("running hook {}", quoted_path(&hook_path));
+    let mut command = std::process::Command::new(&hook_path);
+    command.env("GIT_DIR", repo.path());
+    if let Some(state_dir_path) = state_dir_path() {
+        // Like `GIT_DIR`, so that git-ibundle run by the hook finds its state.
+        command.env(STATE_DIR_ENV, state_dir_path);
+    }
+    let mut child = command
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(std::process::Stdio::piped())
+        .stdout(io::stderr())
+        .spawn()
+        .with_context(|| {
+            format!("failed to run hook {}", quoted_path(&hook_path))
+        })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Hooks need not consume their input.
+        match stdin.write_all(stdin_bytes) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(e.into());
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(child.wait()?))
+}
+
+// Formats `old-oid new-oid refname` lines (as for Git's `pre-receive` hook)
+// for each ref that differs between `old_orefs` and `new_orefs`.  `HEAD` is
+// excluded; it's conveyed separately.
+fn ref_update_lines(old_orefs: &ORefs, new_orefs: &ORefs) -> AResult<BString> {
+    let zero = git2::Oid::zero();
+    let names = old_orefs
+        .keys()
+        .chain(new_orefs.keys())
+        .filter(|name| *name != b"HEAD".as_bstr())
+        .collect::<collections::BTreeSet<_>>();
+    let mut lines = Vec::new();
+    for name in names {
+        let old_oid = old_orefs.get(name).unwrap_or(&zero);
+        let new_oid = new_orefs.get(name).unwrap_or(&zero);
+        if old_oid != new_oid {
+            write_oid(&mut lines, old_oid)?;
+            lines.write_all(b" ")?;
+            write_oid_bstr_bline(&mut lines, new_oid, name.as_bstr())?;
+        }
+    }
+    Ok(BString::from(lines))
+}
+
+fn repo_meta_read(
+    repo: &git2::Repository,
+    seq_num: SeqNum,
+) -> AResult<RepoMeta> {
+    let meta_path = repo_meta_path(repo, seq_num)?;
+    let mut f = open_reader(&meta_path)?;
+    RepoMeta::read(&mut f)
+}
+
+fn repo_meta_write(
+    repo: &git2::Repository,
+    seq_num: SeqNum,
+    meta: &RepoMeta,
+) -> AResult<()> {
+    let meta_dir_path = repo_meta_dir_path(repo)?;
+    fs::create_dir_all(&meta_dir_path)?;
+    let meta_path = repo_meta_path(repo, seq_num)?;
+    let mut f = create_writer(&meta_path)?;
+    meta.write(&mut f)
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+struct IBundle {
+    repo_id: BString,
+    seq_num: SeqNum,
+    basis_seq_num: SeqNum,
+    head_ref: BString,
+    head_detached: bool,
+    prereqs: Commits,
+    added_orefs: ORefs,
+    removed_orefs: ORefs,
+    moved_orefs: ORefs,
+    unchanged_orefs: Option<ORefs>,
+    packed_orefs: ORefs,
+}
+
+impl IBundle {
+    fn construct(
+        repo_id: BString,
+        seq_num: SeqNum,
+        basis_seq_num: SeqNum,
+        meta: &RepoMeta,
+        basis_meta: &RepoMeta,
+    ) -> AResult<Self> {
+        let mut removed_orefs = ORefs::new();
+        for (name, &oid) in basis_meta.orefs.iter() {
+            if !meta.orefs.contains_key(name) {
+                removed_orefs.insert(name.clone(), oid);
+            }
+        }
+
+        let mut added_orefs = ORefs::new();
+        let mut moved_orefs = ORefs::new();
+        let mut unchanged_orefs = ORefs::new();
+        for (name, &oid) in meta.orefs.iter() {
+            let name = name.clone();
+            if let Some(&oid2) = basis_meta.orefs.get(&name) {
+                if oid == oid2 {
+                    unchanged_orefs.insert(name, oid);
+                } else {
+                    moved_orefs.insert(name, oid);
+                }
+            } else {
+                added_orefs.insert(name, oid);
+            }
+        }
+
+        let ibundle = IBundle {
+            repo_id,
+            seq_num,
+            basis_seq_num,
+            head_ref: meta.head_ref.clone(),
+            head_detached: meta.head_detached,
+            prereqs: Commits::new(),
+            added_orefs,
+            removed_orefs,
+            moved_orefs,
+            unchanged_orefs: Some(unchanged_orefs),
+            packed_orefs: ORefs::new(),
+        };
+
+        Ok(ibundle)
+    }
+
+    fn new() -> Self {
+        Self {
+            repo_id: BString::from(""),
+            seq_num: 0,
+            basis_seq_num: 0,
+            head_ref: BString::from(""),
+            head_detached: false,
+            prereqs: Commits::new(),
+            added_orefs: ORefs::new(),
+            removed_orefs: ORefs::new(),
+            moved_orefs: ORefs::new(),
+            unchanged_orefs: None,
+            packed_orefs: ORefs::new(),
+        }
+    }
+
+    fn read<R: io::BufRead>(reader: &mut R) -> AResult<Self> {
+        let mut bline = BString::from("");
+        read_bline(reader, &mut bline)?;
+        if bline != IBUNDLE_FORMAT_V2 {
+            bail!("not a V2 ibundle file");
+        }
+
+        let mut ibundle = Self::new();
+        let mut added_packed_orefs = ORefs::new();
+        let mut added_not_packed_orefs = ORefs::new();
+        let mut moved_packed_orefs = ORefs::new();
+        let mut moved_not_packed_orefs = ORefs::new();
+        while read_bline(reader, &mut bline)? > 0 {
+            if bline.starts_with(b"%") {
+                let (dir, rest) = bstr_pop_word(bline[1..].as_bstr());
+                if dir == Directive::REPO_ID {
+                    ibundle.repo_id = BString::from(rest);
+                } else if dir == Directive::SEQ_NUM {
+                    ibundle.seq_num = parse_seq_num(rest)?;
                 } else if dir == Directive::BASIS_SEQ_NUM {
                     ibundle.basis_seq_num = parse_seq_num(rest)?;
                 } else if dir == Directive::HEAD_REF {
@@ -1038,205 +3058,1430 @@ impl IBundle {
                 } else {
                     bail!("invalid ibundle directive {}", bline);
                 }
-            } else {
-                bail!("invalid ibundle line {}", bline);
+            } else {
+                bail!("invalid ibundle line {}", bline);
+            }
+        }
+        ibundle.added_orefs = added_packed_orefs
+            .iter()
+            .chain(added_not_packed_orefs.iter())
+            .collect_orefs();
+        ibundle.moved_orefs = moved_packed_orefs
+            .iter()
+            .chain(moved_not_packed_orefs.iter())
+            .collect_orefs();
+        ibundle.packed_orefs = added_packed_orefs
+            .into_iter()
+            .chain(moved_packed_orefs)
+            .collect();
+
+        Ok(ibundle)
+    }
+
+    fn write<W: io::Write>(
+        &self,
+        writer: &mut W,
+        standalone: bool,
+    ) -> AResult<()> {
+        let unchanged_orefs = if let Some(unchanged) = &self.unchanged_orefs {
+            unchanged
+        } else {
+            bail!("trying to write ibundle without unchanged_refs");
+        };
+        writer.write_all(IBUNDLE_FORMAT_V2)?;
+        writer.write_all(b"\n")?;
+        write_directive(writer, Directive::REPO_ID, &self.repo_id)?;
+        write_directive(
+            writer,
+            Directive::SEQ_NUM,
+            format!("{}", self.seq_num),
+        )?;
+        write_directive(
+            writer,
+            Directive::BASIS_SEQ_NUM,
+            format!("{}", self.basis_seq_num),
+        )?;
+        write_directive(writer, Directive::HEAD_REF, &self.head_ref)?;
+        write_directive_bool(
+            writer,
+            Directive::HEAD_DETACHED,
+            self.head_detached,
+        )?;
+        write_directive(writer, Directive::PREREQS, "")?;
+        commits_write(&self.prereqs, writer)?;
+        write_directive(writer, Directive::ADDED_PACKED_OREFS, "")?;
+        orefs_write(
+            self.added_orefs
+                .iter()
+                .filter(|(name, _oid)| self.packed_orefs.contains_key(*name)),
+            writer,
+        )?;
+        write_directive(writer, Directive::ADDED_NOT_PACKED_OREFS, "")?;
+        orefs_write(
+            self.added_orefs
+                .iter()
+                .filter(|(name, _oid)| !self.packed_orefs.contains_key(*name)),
+            writer,
+        )?;
+        write_directive(writer, Directive::REMOVED_OREFS, "")?;
+        orefs_write(&self.removed_orefs, writer)?;
+        write_directive(writer, Directive::MOVED_PACKED_OREFS, "")?;
+        orefs_write(
+            self.moved_orefs
+                .iter()
+                .filter(|(name, _oid)| self.packed_orefs.contains_key(*name)),
+            writer,
+        )?;
+        write_directive(writer, Directive::MOVED_NOT_PACKED_OREFS, "")?;
+        orefs_write(
+            self.moved_orefs
+                .iter()
+                .filter(|(name, _oid)| !self.packed_orefs.contains_key(*name)),
+            writer,
+        )?;
+        if standalone {
+            write_directive(writer, Directive::UNCHANGED_OREFS, "")?;
+            orefs_write(unchanged_orefs, writer)?;
+        }
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn validate_repo_identity(
+        &self,
+        repo: &git2::Repository,
+        force: bool,
+    ) -> AResult<()> {
+        if let Some(repo_id) = repo_id_read(repo) {
+            if repo_id != self.repo_id {
+                bail!(StatusError::new(
+                    STATUS_WRONG_REPO_ID,
+                    format!(
+                        "repo's repo_id({}) != ibundle repo_id({})",
+                        repo_id, self.repo_id
+                    )
+                ));
+            }
+        } else if !force && !repo_is_empty(repo)? {
+            bail!(StatusError::new(
+                STATUS_MISSING_REPO_ID,
+                "repo lacks repo_id and is non-empty; consider `--force`"
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn determine_basis_meta(
+        &self,
+        repo: &git2::Repository,
+        force: bool,
+    ) -> AResult<RepoMeta> {
+        let basis_meta = if self.basis_seq_num == 0 {
+            RepoMeta::new()
+        } else if repo_has_basis(repo, &self.basis_seq_num) {
+            repo_meta_read(repo, self.basis_seq_num)?
+        } else if self.unchanged_orefs.is_none() {
+            bail!(StatusError::new(
+                STATUS_BASIS_MISSING,
+                format!(
+                    std::concat!(
+                        "repo missing basis_seq_num={} and ibundle is not ",
+                        "standalone; consider `create --standalone`"
+                    ),
+                    self.basis_seq_num
+                )
+            ));
+        } else if !force {
+            bail!(StatusError::new(
+                STATUS_BASIS_MISSING_STANDALONE,
+                format!(
+                    std::concat!(
+                        "repo missing basis_seq_num={}, but ibundle is ",
+                        "standalone; consider `--force`",
+                    ),
+                    self.basis_seq_num
+                )
+            ));
+        } else {
+            RepoMeta::new()
+        };
+
+        Ok(basis_meta)
+    }
+
+    fn apply_basis_meta(&mut self, basis_meta: &RepoMeta) -> AResult<()> {
+        if self.unchanged_orefs.is_none() {
+            let mut unchanged_orefs = ORefs::new();
+            for (name, &oid) in basis_meta.orefs.iter() {
+                if !self.removed_orefs.contains_key(name)
+                    && !self.moved_orefs.contains_key(name)
+                {
+                    unchanged_orefs.insert(name.clone(), oid);
+                }
+            }
+            self.unchanged_orefs = Some(unchanged_orefs);
+        }
+        Ok(())
+    }
+
+    fn validate_and_apply_basis(
+        &mut self,
+        repo: &git2::Repository,
+        force: bool,
+    ) -> AResult<()> {
+        self.validate_repo_identity(repo, force)?;
+        let basis_meta = self.determine_basis_meta(repo, force)?;
+        self.apply_basis_meta(&basis_meta)?;
+        Ok(())
+    }
+
+    fn delta_orefs(&self) -> AResult<ORefs> {
+        Ok(self
+            .added_orefs
+            .iter()
+            .chain(self.moved_orefs.iter())
+            .collect_orefs())
+    }
+
+    fn full_orefs(&self) -> AResult<ORefs> {
+        let mut orefs = self.delta_orefs()?;
+        if let Some(unchanged_orefs) = &self.unchanged_orefs {
+            orefs.extend(unchanged_orefs.clone());
+        } else {
+            bail!("using full_refs() on ibundle without `unchanged_orefs`");
+        }
+        Ok(orefs)
+    }
+
+    // Environment variables describing the ibundle header for hooks.
+    fn hook_env(&self) -> Vec<(&'static str, ffi::OsString)> {
+        vec![
+            ("IBUNDLE_REPO_ID", self.repo_id.to_os_str_lossy().into()),
+            ("IBUNDLE_SEQ_NUM", self.seq_num.to_string().into()),
+            (
+                "IBUNDLE_BASIS_SEQ_NUM",
+                self.basis_seq_num.to_string().into(),
+            ),
+            ("IBUNDLE_HEAD_REF", self.head_ref.to_os_str_lossy().into()),
+            (
+                "IBUNDLE_HEAD_DETACHED",
+                bool_as_bstr(self.head_detached).to_string().into(),
+            ),
+        ]
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "seq_num {}, added {}, removed {}, moved {}, unchanged {}",
+            self.seq_num,
+            self.added_orefs.len(),
+            self.removed_orefs.len(),
+            self.moved_orefs.len(),
+            if let Some(unchanged_orefs) = &self.unchanged_orefs {
+                format!("{}", unchanged_orefs.len())
+            } else {
+                "???".to_string()
+            }
+        )
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+// Content policy for newly transferred blobs, read from a file of rules (one
+// per line):
+//
+//   # Comment.
+//   max-blob-size <SIZE>[K|M|G]
+//   forbid-path <GLOB>
+//   forbid-content <REGEX>
+#[derive(Debug)]
+struct Policy {
+    max_blob_size: Option<u64>,
+    forbidden_paths: Vec<BString>,
+    forbidden_contents: Vec<regex::bytes::Regex>,
+}
+
+fn parse_size(bstr: &BStr) -> AResult<u64> {
+    let s = bstr.to_str()?;
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()?
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("size {} too large", quoted(bstr)))
+}
+
+impl Policy {
+    fn read<R: io::BufRead>(reader: &mut R) -> AResult<Self> {
+        let mut policy = Policy {
+            max_blob_size: None,
+            forbidden_paths: Vec::new(),
+            forbidden_contents: Vec::new(),
+        };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        for (i, line) in ByteSlice::lines(data.as_slice()).enumerate() {
+            let line = line.trim().as_bstr();
+            if !line.is_empty() && !line.starts_with(b"#") {
+                policy
+                    .add_rule(line)
+                    .with_context(|| format!("policy line {}", i + 1))?;
+            }
+        }
+        Ok(policy)
+    }
+
+    fn add_rule(&mut self, line: &BStr) -> AResult<()> {
+        let (rule, rest) = bstr_pop_word(line);
+        let rest = rest.trim().as_bstr();
+        if rule == "max-blob-size" {
+            self.max_blob_size = Some(parse_size(rest)?);
+        } else if rule == "forbid-path" {
+            self.forbidden_paths.push(BString::from(rest));
+        } else if rule == "forbid-content" {
+            let re = regex::bytes::Regex::new(rest.to_str()?)?;
+            self.forbidden_contents.push(re);
+        } else {
+            bail!("unknown rule {}", quoted(rule));
+        }
+        Ok(())
+    }
+
+    // An empty `path` (for a blob referenced directly by a ref or tag) is
+    // exempt from path rules.
+    fn blob_violations(&self, path: &BStr, blob: &git2::Blob) -> Vec<String> {
+        let what = if path.is_empty() {
+            format!("blob {}", blob.id())
+        } else {
+            quoted(path)
+        };
+        let mut violations = Vec::new();
+        for pattern in self.forbidden_paths.iter() {
+            if !path.is_empty() && glob_match(pattern, path) {
+                violations.push(format!(
+                    "{}: forbidden path (forbid-path {})",
+                    what, pattern
+                ));
+            }
+        }
+        if let Some(max_blob_size) = self.max_blob_size {
+            let size = blob.size() as u64;
+            if size > max_blob_size {
+                violations.push(format!(
+                    "{}: size {} exceeds max-blob-size {}",
+                    what, size, max_blob_size
+                ));
+            }
+        }
+        for re in self.forbidden_contents.iter() {
+            if re.is_match(blob.content()) {
+                violations.push(format!(
+                    "{}: content matches forbid-content {}",
+                    what, re
+                ));
+            }
+        }
+        violations
+    }
+}
+
+// Blobs with their paths.
+type PathBlobs<'r> = Vec<(BString, git2::Blob<'r>)>;
+
+// Blobs introduced by `commit` relative to its parents, with their paths.
+//
+// For merges, a blob matching the same path in any parent is not new.
+fn commit_new_blobs<'r>(
+    repo: &'r git2::Repository,
+    commit: &git2::Commit,
+) -> AResult<PathBlobs<'r>> {
+    let tree = commit.tree()?;
+    let parent_trees = commit
+        .parents()
+        .map(|parent| parent.tree())
+        .collect::<Result<Vec<_>, _>>()?;
+    let diff =
+        repo.diff_tree_to_tree(parent_trees.first(), Some(&tree), None)?;
+    let mut blobs = Vec::new();
+    for delta in diff.deltas() {
+        let new_file = delta.new_file();
+        if !new_file.exists() || new_file.mode() == git2::FileMode::Commit {
+            continue;
+        }
+        let (path, path_bytes) = match (new_file.path(), new_file.path_bytes())
+        {
+            (Some(path), Some(path_bytes)) => (path, path_bytes),
+            _ => continue,
+        };
+        let in_other_parent = parent_trees.iter().skip(1).any(|parent_tree| {
+            parent_tree
+                .get_path(path)
+                .map(|entry| entry.id() == new_file.id())
+                .unwrap_or(false)
+        });
+        if !in_other_parent {
+            blobs.push((
+                BString::from(path_bytes),
+                repo.find_blob(new_file.id())?,
+            ));
+        }
+    }
+    Ok(blobs)
+}
+
+// Commits reachable from `orefs` but not from `excluded_oids` (i.e., the
+// commits an ibundle's pack transfers), newest first.
+fn repo_new_commits<'a>(
+    repo: &git2::Repository,
+    orefs: &ORefs,
+    excluded_oids: impl IntoIterator<Item = &'a git2::Oid>,
+) -> AResult<Vec<git2::Oid>> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    for (_name, &oid) in orefs.iter() {
+        if let Ok(commit) = repo_commit(repo, oid) {
+            walk.push(commit.id())?;
+        }
+    }
+    for &oid in excluded_oids.into_iter() {
+        if let Ok(commit) = repo_commit(repo, oid) {
+            walk.hide(commit.id())?;
+        }
+    }
+    Ok(walk.collect::<Result<Vec<_>, _>>()?)
+}
+
+// Blobs within `tree`, with their paths.
+fn tree_blobs<'r>(
+    repo: &'r git2::Repository,
+    tree: &git2::Tree,
+) -> AResult<PathBlobs<'r>> {
+    let mut blobs = Vec::new();
+    let mut walk_result = Ok(());
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            return git2::TreeWalkResult::Ok;
+        }
+        match repo.find_blob(entry.id()) {
+            Ok(blob) => {
+                let mut path = BString::from(dir);
+                path.push_str(entry.name_bytes());
+                blobs.push((path, blob));
+                git2::TreeWalkResult::Ok
+            }
+            Err(e) => {
+                walk_result = Err(e);
+                git2::TreeWalkResult::Abort
+            }
+        }
+    })?;
+    walk_result?;
+    Ok(blobs)
+}
+
+// Blobs that `orefs` reference outside of commits (i.e., via refs or tags
+// pointing directly at trees or blobs) and not via `excluded_oids`, with
+// a label for each such ref.  As when packing, these trees are transferred
+// whole.
+fn ref_target_blobs<'r>(
+    repo: &'r git2::Repository,
+    orefs: &ORefs,
+    excluded_oids: &collections::HashSet<&git2::Oid>,
+) -> AResult<Vec<(String, PathBlobs<'r>)>> {
+    let mut ref_blobs = Vec::new();
+    for (name, &oid) in orefs.iter() {
+        let mut target = repo.find_object(oid, None)?;
+        while let Some(tag) = target.as_tag() {
+            let tag_target = tag.target()?;
+            target = tag_target;
+        }
+        if excluded_oids.contains(&target.id()) {
+            continue;
+        }
+        let label = match target.kind() {
+            Some(git2::ObjectType::Tree) => {
+                format!("ref {} (tree {})", quoted(name), target.id())
+            }
+            Some(git2::ObjectType::Blob) => {
+                format!("ref {} (blob {})", quoted(name), target.id())
+            }
+            _ => continue,
+        };
+        let blobs = if let Some(tree) = target.as_tree() {
+            tree_blobs(repo, tree)?
+        } else {
+            vec![(BString::from(""), target.peel_to_blob()?)]
+        };
+        ref_blobs.push((label, blobs));
+    }
+    Ok(ref_blobs)
+}
+
+// Checks commits reachable from `orefs` but not from `excluded_oids`, as
+// well as trees and blobs referenced directly, returning violations labeled
+// by each offending commit or ref.
+fn repo_policy_violations(
+    repo: &git2::Repository,
+    policy: &Policy,
+    orefs: &ORefs,
+    excluded_oids: &collections::HashSet<&git2::Oid>,
+) -> AResult<Vec<(String, Vec<String>)>> {
+    let mut labeled_violations = Vec::new();
+    for commit_id in
+        repo_new_commits(repo, orefs, excluded_oids.iter().copied())?
+            .into_iter()
+            .rev()
+    {
+        let commit = repo.find_commit(commit_id)?;
+        let mut violations = Vec::new();
+        for (path, blob) in commit_new_blobs(repo, &commit)? {
+            violations.extend(policy.blob_violations(path.as_bstr(), &blob));
+        }
+        if !violations.is_empty() {
+            labeled_violations.push((
+                format!(
+                    "commit {} {}",
+                    commit.id(),
+                    quoted(commit_comment(&commit))
+                ),
+                violations,
+            ));
+        }
+    }
+    for (label, blobs) in ref_target_blobs(repo, orefs, excluded_oids)? {
+        let mut violations = Vec::new();
+        for (path, blob) in blobs {
+            violations.extend(policy.blob_violations(path.as_bstr(), &blob));
+        }
+        if !violations.is_empty() {
+            labeled_violations.push((label, violations));
+        }
+    }
+    Ok(labeled_violations)
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+// Allowed author/committer email patterns for refs matching `ref_pattern`.
+struct IdentityScope {
+    ref_pattern: BString,
+    allowed_emails: Vec<BString>,
+}
+
+// Each ref is governed by the first scope whose pattern matches it; refs
+// matching no scope are unchecked.
+struct IdentityPolicy {
+    scopes: Vec<IdentityScope>,
+}
+
+impl IdentityPolicy {
+    fn read<R: io::BufRead>(reader: &mut R) -> AResult<Self> {
+        let mut policy = IdentityPolicy { scopes: Vec::new() };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        for (i, line) in ByteSlice::lines(data.as_slice()).enumerate() {
+            let line = line.trim().as_bstr();
+            if !line.is_empty() && !line.starts_with(b"#") {
+                policy.add_rule(line).with_context(|| {
+                    format!("identity policy line {}", i + 1)
+                })?;
+            }
+        }
+        Ok(policy)
+    }
+
+    fn add_rule(&mut self, line: &BStr) -> AResult<()> {
+        let (rule, rest) = bstr_pop_word(line);
+        let rest = rest.trim().as_bstr();
+        if rule == "ref" {
+            self.scopes.push(IdentityScope {
+                ref_pattern: BString::from(rest),
+                allowed_emails: Vec::new(),
+            });
+        } else if rule == "allow-email" {
+            match self.scopes.last_mut() {
+                Some(scope) => {
+                    scope.allowed_emails.push(rest.to_ascii_lowercase().into())
+                }
+                None => bail!("rule {} must follow a 'ref' rule", quoted(rule)),
+            }
+        } else {
+            bail!("unknown rule {}", quoted(rule));
+        }
+        Ok(())
+    }
+
+    fn scope_for(&self, ref_name: &BStr) -> Option<&IdentityScope> {
+        self.scopes
+            .iter()
+            .find(|scope| glob_match(&scope.ref_pattern, ref_name))
+    }
+}
+
+impl IdentityScope {
+    fn allows(&self, email: &[u8]) -> bool {
+        let email = email.to_ascii_lowercase();
+        self.allowed_emails
+            .iter()
+            .any(|pattern| glob_match(pattern, &email))
+    }
+}
+
+// Checks author and committer emails of commits newly introduced by each of
+// `bundle_orefs` (i.e., not reachable from `old_orefs`) against
+// `identity_policy`, reporting one line per violation.
+fn quarantine_check_identities(
+    quarantine_path: &path::Path,
+    identity_policy: &IdentityPolicy,
+    bundle_orefs: &ORefs,
+    old_orefs: &ORefs,
+) -> AResult<()> {
+    let quarantine = repo_open(quarantine_path)?;
+    let mut violations = Vec::new();
+    for (name, &oid) in bundle_orefs.iter() {
+        let scope = match identity_policy.scope_for(name.as_bstr()) {
+            Some(scope) => scope,
+            None => continue,
+        };
+        let ref_orefs = [(name, &oid)].into_iter().collect_orefs();
+        for commit_id in
+            repo_new_commits(&quarantine, &ref_orefs, old_orefs.values())?
+        {
+            let commit = quarantine.find_commit(commit_id)?;
+            for (role, signature) in [
+                ("author", commit.author()),
+                ("committer", commit.committer()),
+            ] {
+                if !scope.allows(signature.email_bytes()) {
+                    violations.push(format!(
+                        "{} {} {} {}",
+                        quoted(name),
+                        commit_id,
+                        role,
+                        quoted(signature.email_bytes())
+                    ));
+                }
+            }
+        }
+    }
+
+    if !violations.is_empty() {
+        if log_enabled!(Level::Error) {
+            eprintln!(
+                "ibundle has {} identity policy violations:",
+                violations.len()
+            );
+            for violation in violations.iter() {
+                eprintln!("  {}", violation);
+            }
+        }
+        bail!("ibundle history failed identity checks; refusing to fetch");
+    }
+    log::info!("ibundle history passed identity checks");
+    Ok(())
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RefChange {
+    Created,
+    Deleted,
+    FastForward,
+    Forced,
+    // The old value (or an object needed for classification) is unknown
+    // (e.g., the basis metadata is unavailable).
+    Moved,
+}
+
+impl RefChange {
+    fn classify(
+        repo: &git2::Repository,
+        old_oid: Option<git2::Oid>,
+        new_oid: Option<git2::Oid>,
+    ) -> Self {
+        match (old_oid, new_oid) {
+            (None, _) => RefChange::Created,
+            (_, None) => RefChange::Deleted,
+            (Some(old_oid), Some(new_oid))
+                if !repo_has_oid(repo, old_oid)
+                    || !repo_has_oid(repo, new_oid) =>
+            {
+                RefChange::Moved
+            }
+            (Some(old_oid), Some(new_oid)) => {
+                match (repo_commit(repo, old_oid), repo_commit(repo, new_oid)) {
+                    (Ok(old_commit), Ok(new_commit))
+                        if old_oid == old_commit.id()
+                            && new_oid == new_commit.id()
+                            && repo
+                                .graph_descendant_of(new_oid, old_oid)
+                                .unwrap_or(false) =>
+                    {
+                        RefChange::FastForward
+                    }
+                    _ => RefChange::Forced,
+                }
+            }
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            RefChange::Created => "new",
+            RefChange::Deleted => "deleted",
+            RefChange::FastForward => "fast-forward",
+            RefChange::Forced => "forced update",
+            RefChange::Moved => "moved",
+        }
+    }
+
+    // Stable identifier for `--format json`.
+    fn code(&self) -> &'static str {
+        match self {
+            RefChange::Created => "created",
+            RefChange::Deleted => "deleted",
+            RefChange::FastForward => "fast_forward",
+            RefChange::Forced => "forced",
+            RefChange::Moved => "moved",
+        }
+    }
+}
+
+// `(name, old, new, change)` for a changed reference.
+type RefChangeItem = (RefName, Option<git2::Oid>, Option<git2::Oid>, RefChange);
+
+// Reference changes carried by `ibundle`.  Without `old_orefs` (the basis
+// references), moved references cannot be classified.
+fn ibundle_ref_changes(
+    repo: &git2::Repository,
+    ibundle: &IBundle,
+    old_orefs: Option<&ORefs>,
+) -> Vec<RefChangeItem> {
+    let mut changes = Vec::new();
+    for (name, &oid) in ibundle.added_orefs.iter() {
+        changes.push((name.clone(), None, Some(oid), RefChange::Created));
+    }
+    for (name, &oid) in ibundle.removed_orefs.iter() {
+        changes.push((name.clone(), Some(oid), None, RefChange::Deleted));
+    }
+    for (name, &oid) in ibundle.moved_orefs.iter() {
+        let old_oid = old_orefs.and_then(|orefs| orefs.get(name)).copied();
+        let change = if old_oid.is_some() {
+            RefChange::classify(repo, old_oid, Some(oid))
+        } else {
+            RefChange::Moved
+        };
+        changes.push((name.clone(), old_oid, Some(oid), change));
+    }
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+// Reference changes going from `old_orefs` to `new_orefs`.
+fn orefs_ref_changes(
+    repo: &git2::Repository,
+    old_orefs: &ORefs,
+    new_orefs: &ORefs,
+) -> Vec<RefChangeItem> {
+    let names = old_orefs
+        .keys()
+        .chain(new_orefs.keys())
+        .collect::<collections::BTreeSet<_>>();
+    let mut changes = Vec::new();
+    for name in names {
+        let old_oid = old_orefs.get(name).copied();
+        let new_oid = new_orefs.get(name).copied();
+        if old_oid != new_oid {
+            let change = RefChange::classify(repo, old_oid, new_oid);
+            changes.push((name.clone(), old_oid, new_oid, change));
+        }
+    }
+    changes
+}
+
+fn abbrev_oid(oid: Option<git2::Oid>) -> String {
+    let mut s = oid.unwrap_or_else(git2::Oid::zero).to_string();
+    s.truncate(7);
+    s
+}
+
+// Reports `changes` (other than to `HEAD`) one per line in the style of `git
+// fetch`; with `porcelain`, prints machine-readable lines to stdout in the
+// style of `git fetch --porcelain`:
+//
+//   <flag> <old-oid> <new-oid> <refname>
+fn report_ref_changes(changes: &[RefChangeItem], porcelain: bool) {
+    let changes = changes
+        .iter()
+        .filter(|(name, ..)| name != b"HEAD".as_bstr())
+        .collect::<Vec<_>>();
+    if !porcelain {
+        log::info!("ref updates: {}", changes.len());
+    }
+    for (name, old_oid, new_oid, change) in changes {
+        let is_tag = name.starts_with(b"refs/tags/");
+        let (flag, summary, note) = match change {
+            RefChange::Created if name.starts_with(b"refs/heads/") => {
+                ('*', "[new branch]".to_string(), "")
+            }
+            RefChange::Created if is_tag => ('*', "[new tag]".to_string(), ""),
+            RefChange::Created => ('*', "[new ref]".to_string(), ""),
+            RefChange::Deleted => ('-', "[deleted]".to_string(), ""),
+            RefChange::FastForward | RefChange::Forced if is_tag => {
+                ('t', "[tag update]".to_string(), "")
+            }
+            RefChange::FastForward => (
+                ' ',
+                format!("{}..{}", abbrev_oid(*old_oid), abbrev_oid(*new_oid)),
+                "",
+            ),
+            RefChange::Forced => (
+                '+',
+                format!("{}...{}", abbrev_oid(*old_oid), abbrev_oid(*new_oid)),
+                "  (forced update)",
+            ),
+            RefChange::Moved => (' ', "[updated]".to_string(), ""),
+        };
+        if porcelain {
+            println!(
+                "{} {} {} {}",
+                flag,
+                old_oid.unwrap_or_else(git2::Oid::zero),
+                new_oid.unwrap_or_else(git2::Oid::zero),
+                name
+            );
+        } else {
+            log::info!(" {} {:<17} {}{}", flag, summary, quoted(name), note);
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+// Version of the `--format json` document layout (see README.md); bump only
+// for incompatible changes.
+const JSON_SCHEMA_VERSION: u64 = 1;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Structured results accumulated by a command; with `--format json`, written
+// to stdout as a single document when the command completes.
+struct Report {
+    format: OutputFormat,
+    command: &'static str,
+    result: serde_json::Map<String, serde_json::Value>,
+    message: Option<String>,
+}
+
+impl Report {
+    fn new(format: OutputFormat, command: &'static str) -> Self {
+        Self {
+            format,
+            command,
+            result: serde_json::Map::new(),
+            message: None,
+        }
+    }
+
+    fn is_json(&self) -> bool {
+        self.format == OutputFormat::Json
+    }
+
+    fn set<V: Into<serde_json::Value>>(&mut self, key: &str, value: V) {
+        self.result.insert(key.to_string(), value.into());
+    }
+
+    fn write(self, exit_status: i32) -> AResult<()> {
+        if !self.is_json() {
+            return Ok(());
+        }
+        let mut doc = serde_json::json!({
+            "schema_version": JSON_SCHEMA_VERSION,
+            "command": self.command,
+            "ok": exit_status == STATUS_OK,
+            "result": self.result,
+        });
+        if exit_status != STATUS_OK {
+            doc["error"] = serde_json::json!({
+                "code": status_code(exit_status),
+                "status": exit_status,
+                "message": self.message.unwrap_or_default(),
+            });
+        }
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &doc)?;
+        stdout.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+// Machine-readable name for each exit status.
+fn status_code(exit_status: i32) -> &'static str {
+    match exit_status {
+        STATUS_OK => "ok",
+        STATUS_EMPTY_BUNDLE => "empty_ibundle",
+        STATUS_MASS_CHANGE => "mass_change",
+        STATUS_WRONG_REPO_ID => "wrong_repo_id",
+        STATUS_MISSING_REPO_ID => "missing_repo_id",
+        STATUS_BASIS_MISSING => "basis_missing",
+        STATUS_BASIS_MISSING_STANDALONE => "basis_missing_standalone",
+        STATUS_MISSING_PREREQS => "missing_prereqs",
+        STATUS_MISSING_OREFS => "missing_orefs",
+        STATUS_BASIS_UNAVAILABLE => "basis_unavailable",
+        STATUS_LOCKED => "locked",
+        _ => "error",
+    }
+}
+
+fn json_bstr(s: &BStr) -> serde_json::Value {
+    s.to_str_lossy().into()
+}
+
+fn json_oid(oid: Option<git2::Oid>) -> serde_json::Value {
+    match oid {
+        Some(oid) => oid.to_string().into(),
+        None => serde_json::Value::Null,
+    }
+}
+
+fn json_orefs(orefs: &ORefs) -> serde_json::Value {
+    orefs
+        .iter()
+        .map(|(name, oid)| (name.to_str_lossy().into(), json_oid(Some(*oid))))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn json_commits(commits: &Commits) -> serde_json::Value {
+    commits
+        .iter()
+        .map(|(oid, comment)| (oid.to_string(), json_bstr(comment.as_bstr())))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn json_ref_changes(changes: &[RefChangeItem]) -> serde_json::Value {
+    changes
+        .iter()
+        .filter(|(name, ..)| name != b"HEAD".as_bstr())
+        .map(|(name, old_oid, new_oid, change)| {
+            serde_json::json!({
+                "ref": json_bstr(name.as_bstr()),
+                "old": json_oid(*old_oid),
+                "new": json_oid(*new_oid),
+                "change": change.code(),
+            })
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// Counts refs (other than `HEAD`) in `old_orefs` missing from `new_orefs`.
+fn count_removed_refs(old_orefs: &ORefs, new_orefs: &ORefs) -> usize {
+    old_orefs
+        .keys()
+        .filter(|name| {
+            *name != b"HEAD".as_bstr() && !new_orefs.contains_key(*name)
+        })
+        .count()
+}
+
+// Returns refs (other than `HEAD`) moved non-fast-forward from `old_orefs` to
+// `new_orefs`; `repo` must contain the objects for both.
+fn forced_refs<'a>(
+    repo: &git2::Repository,
+    old_orefs: &ORefs,
+    new_orefs: &'a ORefs,
+) -> Vec<&'a RefName> {
+    new_orefs
+        .iter()
+        .filter_map(|(name, &new_oid)| match old_orefs.get(name) {
+            Some(&old_oid)
+                if name != b"HEAD".as_bstr()
+                    && old_oid != new_oid
+                    && RefChange::classify(
+                        repo,
+                        Some(old_oid),
+                        Some(new_oid),
+                    ) == RefChange::Forced =>
+            {
+                Some(name)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Refuses any of the `forced` or `removed` refs that match
+// `fast_forward_only` patterns, reporting each as `old...new` (or as
+// deleted); removing a ref is no fast-forward either.
+fn check_fast_forward_only(
+    forced: &[&RefName],
+    removed: &[&RefName],
+    fast_forward_only: &[BString],
+    old_orefs: &ORefs,
+    new_orefs: &ORefs,
+) -> AResult<()> {
+    let violations = forced
+        .iter()
+        .chain(removed.iter())
+        .filter(|name| {
+            fast_forward_only
+                .iter()
+                .any(|pattern| glob_match(pattern, name))
+        })
+        .collect::<Vec<_>>();
+    if !violations.is_empty() {
+        if log_enabled!(Level::Error) {
+            eprintln!(
+                "ibundle moves {} fast-forward-only refs non-fast-forward:",
+                violations.len()
+            );
+            for &&name in violations.iter() {
+                match new_orefs.get(name) {
+                    Some(new_oid) => eprintln!(
+                        "  {}: forced update {}...{}",
+                        quoted(name),
+                        old_orefs[name],
+                        new_oid
+                    ),
+                    None => eprintln!(
+                        "  {}: deleted (was {})",
+                        quoted(name),
+                        old_orefs[name]
+                    ),
+                }
             }
         }
-        ibundle.added_orefs = added_packed_orefs
-            .iter()
-            .chain(added_not_packed_orefs.iter())
-            .collect_orefs();
-        ibundle.moved_orefs = moved_packed_orefs
-            .iter()
-            .chain(moved_not_packed_orefs.iter())
-            .collect_orefs();
-        ibundle.packed_orefs = added_packed_orefs
-            .into_iter()
-            .chain(moved_packed_orefs.into_iter())
-            .collect();
+        bail!(
+            "non-fast-forward update of fast-forward-only refs; \
+            refusing to fetch"
+        );
+    }
+    Ok(())
+}
 
-        Ok(ibundle)
+// Describes the violation if removing `num_removed` of `num_old_refs` refs
+// exceeds `max_removed_percent`.
+fn removed_refs_violation(
+    num_removed: usize,
+    num_old_refs: usize,
+    max_removed_percent: u64,
+) -> Option<String> {
+    let num_removed = num_removed as u64;
+    let num_old_refs = num_old_refs as u64;
+    if num_removed.saturating_mul(100)
+        > max_removed_percent.saturating_mul(num_old_refs)
+    {
+        Some(format!(
+            "removes {} of {} refs ({}%), exceeding \
+            ibundle.maxRemovedPercent ({}%)",
+            num_removed,
+            num_old_refs,
+            num_removed * 100 / num_old_refs,
+            max_removed_percent
+        ))
+    } else {
+        None
     }
+}
 
-    fn write<W: io::Write>(
-        &self,
-        writer: &mut W,
-        standalone: bool,
-    ) -> AResult<()> {
-        let unchanged_orefs = if let Some(unchanged) = &self.unchanged_orefs {
-            unchanged
-        } else {
-            bail!("trying to write ibundle without unchanged_refs");
-        };
-        writer.write_all(IBUNDLE_FORMAT_V2)?;
-        writer.write_all(b"\n")?;
-        write_directive(writer, Directive::REPO_ID, &self.repo_id)?;
-        write_directive(
-            writer,
-            Directive::SEQ_NUM,
-            &format!("{}", self.seq_num),
-        )?;
-        write_directive(
-            writer,
-            Directive::BASIS_SEQ_NUM,
-            &format!("{}", self.basis_seq_num),
-        )?;
-        write_directive(writer, Directive::HEAD_REF, &self.head_ref)?;
-        write_directive_bool(
-            writer,
-            Directive::HEAD_DETACHED,
-            self.head_detached,
-        )?;
-        write_directive(writer, Directive::PREREQS, "")?;
-        commits_write(&self.prereqs, writer)?;
-        write_directive(writer, Directive::ADDED_PACKED_OREFS, "")?;
-        orefs_write(
-            self.added_orefs
-                .iter()
-                .filter(|(name, _oid)| self.packed_orefs.contains_key(*name)),
-            writer,
-        )?;
-        write_directive(writer, Directive::ADDED_NOT_PACKED_OREFS, "")?;
-        orefs_write(
-            self.added_orefs
-                .iter()
-                .filter(|(name, _oid)| !self.packed_orefs.contains_key(*name)),
-            writer,
-        )?;
-        write_directive(writer, Directive::REMOVED_OREFS, "")?;
-        orefs_write(&self.removed_orefs, writer)?;
-        write_directive(writer, Directive::MOVED_PACKED_OREFS, "")?;
-        orefs_write(
-            self.moved_orefs
-                .iter()
-                .filter(|(name, _oid)| self.packed_orefs.contains_key(*name)),
+// Describes the violation if force-moving `num_forced` refs exceeds
+// `max_forced_refs`.
+fn forced_refs_violation(
+    num_forced: usize,
+    max_forced_refs: u64,
+) -> Option<String> {
+    if num_forced as u64 > max_forced_refs {
+        Some(format!(
+            "force-moves {} refs, exceeding ibundle.maxForcedRefs ({})",
+            num_forced, max_forced_refs
+        ))
+    } else {
+        None
+    }
+}
+
+fn file_sha256<P: AsRef<path::Path>>(path: P) -> AResult<String> {
+    reader_sha256(&mut open_file(path)?)
+}
+
+// Hashes all of `reader` from its start, restoring its position afterward.
+// Hashing an already-open ibundle this way ensures the hash covers the same
+// bytes that are read from it, even if the file is replaced meanwhile.
+fn reader_sha256<R: io::Read + io::Seek>(reader: &mut R) -> AResult<String> {
+    use sha2::Digest;
+    let position = reader.stream_position()?;
+    reader.seek(io::SeekFrom::Start(0))?;
+    let mut hasher = sha2::Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    reader.seek(io::SeekFrom::Start(position))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Converts days since 1970-01-01 into `(year, month, day)`; from
+// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Formats `time` like `git log --date=iso`.
+fn format_git_time(time: &git2::Time) -> String {
+    let offset = i64::from(time.offset_minutes());
+    let local = time.seconds() + offset * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    let secs = local.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60,
+    )
+}
+
+fn delta_status_char(status: git2::Delta) -> char {
+    match status {
+        git2::Delta::Added => 'A',
+        git2::Delta::Deleted => 'D',
+        git2::Delta::Modified => 'M',
+        git2::Delta::Renamed => 'R',
+        git2::Delta::Copied => 'C',
+        git2::Delta::Typechange => 'T',
+        _ => '?',
+    }
+}
+
+fn manifest_write_commit<W: io::Write>(
+    writer: &mut W,
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+) -> AResult<()> {
+    let author = commit.author();
+    writeln!(writer, "### {}", commit.id())?;
+    writeln!(writer)?;
+    writeln!(writer, "- subject: {}", quoted(commit_comment(commit)))?;
+    writeln!(
+        writer,
+        "- author: {} <{}>",
+        author.name_bytes().as_bstr(),
+        author.email_bytes().as_bstr()
+    )?;
+    writeln!(writer, "- date: {}", format_git_time(&author.when()))?;
+    if commit.parent_count() > 1 {
+        writeln!(
             writer,
+            "- merge: {}",
+            commit
+                .parent_ids()
+                .map(|oid| oid.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
         )?;
-        write_directive(writer, Directive::MOVED_NOT_PACKED_OREFS, "")?;
-        orefs_write(
-            self.moved_orefs
-                .iter()
-                .filter(|(name, _oid)| !self.packed_orefs.contains_key(*name)),
+    }
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        None,
+    )?;
+    writeln!(writer, "- files: {}", diff.deltas().len())?;
+    for delta in diff.deltas() {
+        let file = if delta.status() == git2::Delta::Deleted {
+            delta.old_file()
+        } else {
+            delta.new_file()
+        };
+        let path = BStr::new(file.path_bytes().unwrap_or(b""));
+        let size = if file.mode() == git2::FileMode::Commit {
+            "submodule".to_string()
+        } else {
+            format!("{} bytes", repo.find_blob(file.id())?.size())
+        };
+        writeln!(
             writer,
+            "  - {} {} ({})",
+            delta_status_char(delta.status()),
+            quoted(path),
+            size
         )?;
-        if standalone {
-            write_directive(writer, Directive::UNCHANGED_OREFS, "")?;
-            orefs_write(unchanged_orefs, writer)?;
-        }
-        writer.write_all(b"\n")?;
-        Ok(())
     }
+    writeln!(writer)?;
+    Ok(())
+}
 
-    fn validate_repo_identity(
-        &self,
-        repo: &git2::Repository,
-        force: bool,
-    ) -> AResult<()> {
-        if let Some(repo_id) = repo_id_read(&repo) {
-            if repo_id != self.repo_id {
-                bail!(
-                    "repo's repo_id({}) != ibundle repo_id({})",
-                    repo_id,
-                    self.repo_id
-                );
+// Writes a Markdown transfer manifest for `ibundle` (stored at
+// `ibundle_path`), bound to the ibundle file's content by its SHA-256 hash.
+// `repo` must contain all objects in the ibundle's pack; `excluded_oids`
+// bounds the commits transferred by the pack.
+fn manifest_write<'a, W: io::Write>(
+    writer: &mut W,
+    repo: &git2::Repository,
+    ibundle: &IBundle,
+    ibundle_path: &path::Path,
+    standalone: bool,
+    old_orefs: Option<&ORefs>,
+    excluded_oids: impl IntoIterator<Item = &'a git2::Oid>,
+) -> AResult<()> {
+    let ref_changes = ibundle_ref_changes(repo, ibundle, old_orefs);
+    let commit_ids =
+        repo_new_commits(repo, &ibundle.delta_orefs()?, excluded_oids)?;
+
+    writeln!(writer, "# git-ibundle transfer manifest")?;
+    writeln!(writer)?;
+    let ibundle_name = ibundle_path.file_name().unwrap_or_default();
+    writeln!(writer, "- ibundle: {}", quoted_path(ibundle_name))?;
+    writeln!(writer, "- sha256: {}", file_sha256(ibundle_path)?)?;
+    writeln!(writer, "- repo_id: {}", ibundle.repo_id)?;
+    writeln!(writer, "- seq_num: {}", ibundle.seq_num)?;
+    writeln!(writer, "- basis_seq_num: {}", ibundle.basis_seq_num)?;
+    writeln!(writer, "- standalone: {}", yes_no(standalone))?;
+    writeln!(writer, "- head_ref: {}", quoted(&ibundle.head_ref))?;
+    writeln!(writer, "- head_detached: {}", yes_no(ibundle.head_detached))?;
+    writeln!(writer, "- ref_changes: {}", ref_changes.len())?;
+    writeln!(writer, "- commits: {}", commit_ids.len())?;
+    writeln!(writer)?;
+
+    writeln!(writer, "## Reference changes")?;
+    writeln!(writer)?;
+    for (name, old_oid, new_oid, change) in ref_changes.iter() {
+        let oids = match (change, old_oid, new_oid) {
+            (RefChange::FastForward, Some(old_oid), Some(new_oid)) => {
+                format!("{}..{}", old_oid, new_oid)
             }
-        } else if !force && !repo_is_empty(repo)? {
-            bail!("repo lacks repo_id and is non-empty; consider `--force`");
-        }
+            (RefChange::Forced, Some(old_oid), Some(new_oid)) => {
+                format!("{}...{}", old_oid, new_oid)
+            }
+            (_, _, Some(oid)) | (_, Some(oid), None) => oid.to_string(),
+            (_, None, None) => String::new(),
+        };
+        writeln!(writer, "- {}: {} {}", quoted(name), change.describe(), oids)?;
+    }
+    writeln!(writer)?;
 
-        Ok(())
+    writeln!(writer, "## Commits")?;
+    writeln!(writer)?;
+    for commit_id in commit_ids {
+        manifest_write_commit(writer, repo, &repo.find_commit(commit_id)?)?;
     }
+    Ok(())
+}
 
-    fn determine_basis_meta(
-        &self,
-        repo: &git2::Repository,
-        force: bool,
-    ) -> AResult<RepoMeta> {
-        let basis_meta = if self.basis_seq_num == 0 {
-            RepoMeta::new()
-        } else if repo_has_basis(repo, &self.basis_seq_num) {
-            repo_meta_read(&repo, self.basis_seq_num)?
-        } else if self.unchanged_orefs.is_none() {
-            bail!(
-                std::concat!(
-                    "repo missing basis_seq_num={} and ibundle is not ",
-                    "standalone; consider `create --standalone`"
-                ),
-                self.basis_seq_num
-            );
-        } else if !force {
-            bail!(
-                std::concat!(
-                    "repo missing basis_seq_num={}, but ibundle is ",
-                    "standalone; consider `--force`",
-                ),
-                self.basis_seq_num
-            );
-        } else {
-            RepoMeta::new()
-        };
+//////////////////////////////////////////////////////////////////////////////
 
-        Ok(basis_meta)
+const APPROVAL_NAMESPACE: &str = "git-ibundle-approval";
+const SSH_SIGNATURE_BEGIN: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+const SSH_SIGNATURE_END: &[u8] = b"-----END SSH SIGNATURE-----";
+
+// Approvals for `ibundle_path` are accumulated in `<ibundle_path>.approvals`.
+fn approvals_path(ibundle_path: &path::Path) -> path::PathBuf {
+    let mut approvals_path = ibundle_path.as_os_str().to_owned();
+    approvals_path.push(".approvals");
+    approvals_path.into()
+}
+
+// The signed approval message covers only the ibundle's content hash.
+fn approval_message(ibundle_sha256: &str) -> String {
+    format!("git-ibundle approval\nsha256: {}\n", ibundle_sha256)
+}
+
+fn ssh_keygen_sign(key_path: &path::Path, message: &[u8]) -> AResult<BString> {
+    let mut child = std::process::Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", APPROVAL_NAMESPACE, "-f"])
+        .arg(key_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to run ssh-keygen")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "failure in ssh-keygen signing with key {}: {}",
+            quoted_path(key_path),
+            output.stderr.trim().as_bstr()
+        );
     }
+    Ok(BString::from(output.stdout))
+}
 
-    fn apply_basis_meta(&mut self, basis_meta: &RepoMeta) -> AResult<()> {
-        if self.unchanged_orefs.is_none() {
-            let mut unchanged_orefs = ORefs::new();
-            for (name, &oid) in basis_meta.orefs.iter() {
-                if !self.removed_orefs.contains_key(name)
-                    && !self.moved_orefs.contains_key(name)
-                {
-                    unchanged_orefs.insert(name.clone(), oid);
-                }
-            }
-            self.unchanged_orefs = Some(unchanged_orefs);
-        }
-        Ok(())
+// Returns principals in `allowed_signers_path` whose keys match the signature
+// in `sig_path` (without validating the signature itself).
+fn ssh_keygen_find_principals(
+    allowed_signers_path: &path::Path,
+    sig_path: &path::Path,
+) -> AResult<Vec<BString>> {
+    let output = std::process::Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers_path)
+        .arg("-s")
+        .arg(sig_path)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .context("failed to run ssh-keygen")?;
+    if !output.status.success() {
+        return Ok(Vec::new());
     }
+    Ok(ByteSlice::lines(output.stdout.as_slice())
+        .filter(|line| !line.is_empty())
+        .map(BString::from)
+        .collect())
+}
 
-    fn validate_and_apply_basis(
-        &mut self,
-        repo: &git2::Repository,
-        force: bool,
-    ) -> AResult<()> {
-        self.validate_repo_identity(repo, force)?;
-        let basis_meta = self.determine_basis_meta(&repo, force)?;
-        self.apply_basis_meta(&basis_meta)?;
-        Ok(())
+fn ssh_keygen_verify(
+    allowed_signers_path: &path::Path,
+    principal: &BStr,
+    sig_path: &path::Path,
+    message: &[u8],
+) -> AResult<bool> {
+    let mut child = std::process::Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-n", APPROVAL_NAMESPACE, "-f"])
+        .arg(allowed_signers_path)
+        .arg("-I")
+        .arg(principal.to_os_str()?)
+        .arg("-s")
+        .arg(sig_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .context("failed to run ssh-keygen")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message)?;
     }
+    Ok(child.wait()?.success())
+}
 
-    fn delta_orefs(&self) -> AResult<ORefs> {
-        Ok(self
-            .added_orefs
-            .iter()
-            .chain(self.moved_orefs.iter())
-            .collect_orefs())
+// Splits `approvals` into its armored SSH signature blocks.
+fn approval_signatures(approvals: &[u8]) -> Vec<BString> {
+    let mut signatures = Vec::new();
+    let mut signature: Option<BString> = None;
+    for line in ByteSlice::lines_with_terminator(approvals) {
+        let trimmed = line.trim_end();
+        if trimmed == SSH_SIGNATURE_BEGIN {
+            signature = Some(BString::from(""));
+        }
+        if let Some(sig) = signature.as_mut() {
+            sig.push_str(trimmed);
+            sig.push(b'\n');
+            if trimmed == SSH_SIGNATURE_END {
+                signatures.push(signature.take().unwrap_or_default());
+            }
+        }
+    }
+    signatures
+}
+
+// Returns the distinct reviewers (principals in `reviewers_path`, an SSH
+// allowed-signers file) with a valid approval of `ibundle_path`.
+fn repo_ibundle_approvers(
+    repo: &git2::Repository,
+    ibundle_path: &path::Path,
+    ibundle_sha256: &str,
+    reviewers_path: &path::Path,
+) -> AResult<collections::BTreeSet<BString>> {
+    if !reviewers_path.is_file() {
+        bail!("missing reviewers file {}", quoted_path(reviewers_path));
     }
+    let message = approval_message(ibundle_sha256);
+    let approvals_path = approvals_path(ibundle_path);
+    let approvals = if approvals_path.exists() {
+        fs::read(&approvals_path).with_context(|| {
+            format!("failed to read {}", quoted_path(&approvals_path))
+        })?
+    } else {
+        Vec::new()
+    };
 
-    fn full_orefs(&self) -> AResult<ORefs> {
-        let mut orefs = self.delta_orefs()?;
-        if let Some(unchanged_orefs) = &self.unchanged_orefs {
-            orefs.extend(unchanged_orefs.clone().into_iter());
-        } else {
-            bail!("using full_refs() on ibundle without `unchanged_orefs`");
+    let sig_path = repo_mktemp(repo)?.join("approval.sig");
+    let _sig_path_deleter = FileDeleter::new(&sig_path);
+    let mut approvers = collections::BTreeSet::new();
+    for signature in approval_signatures(&approvals) {
+        fs::write(&sig_path, &signature)?;
+        for principal in ssh_keygen_find_principals(reviewers_path, &sig_path)?
+        {
+            if ssh_keygen_verify(
+                reviewers_path,
+                principal.as_bstr(),
+                &sig_path,
+                message.as_bytes(),
+            )? {
+                approvers.insert(principal);
+            }
         }
-        Ok(orefs)
     }
+    Ok(approvers)
+}
 
-    fn summary(&self) -> String {
-        format!(
-            "seq_num {}, added {}, removed {}, moved {}, unchanged {}",
-            self.seq_num,
-            self.added_orefs.len(),
-            self.removed_orefs.len(),
-            self.moved_orefs.len(),
-            if let Some(unchanged_orefs) = &self.unchanged_orefs {
-                format!("{}", unchanged_orefs.len())
-            } else {
-                format!("???")
-            }
-        )
+// Requires at least `ibundle.requiredApprovals` distinct reviewers from
+// `ibundle.reviewersFile` to have approved `ibundle_path`, whose content is
+// read via `ibundle_reader`.
+fn repo_check_approvals<R: io::Read + io::Seek>(
+    repo: &git2::Repository,
+    ibundle_path: &path::Path,
+    ibundle_reader: &mut R,
+) -> AResult<()> {
+    let required =
+        repo_config_u64(repo, "ibundle.requiredApprovals")?.unwrap_or(0);
+    if required == 0 {
+        return Ok(());
+    }
+    let reviewers_path = match repo_config_path(repo, "ibundle.reviewersFile")?
+    {
+        Some(reviewers_path) => reviewers_path,
+        None => bail!(
+            "ibundle.requiredApprovals is set without ibundle.reviewersFile"
+        ),
+    };
+    let approvers = repo_ibundle_approvers(
+        repo,
+        ibundle_path,
+        &reader_sha256(ibundle_reader)?,
+        &reviewers_path,
+    )?;
+    for approver in approvers.iter() {
+        log::info!("approved by {}", quoted(approver));
     }
+    let required = usize::try_from(required).unwrap_or(usize::MAX);
+    if approvers.len() < required {
+        bail!(
+            "ibundle has {} of {} required approvals; refusing to fetch",
+            approvers.len(),
+            required
+        );
+    }
+    Ok(())
 }
 
 //////////////////////////////////////////////////////////////////////////////
@@ -1250,7 +4495,7 @@ fn inc_seq_num(seq_num: &SeqNum) -> AResult<SeqNum> {
 }
 
 fn calc_max_seq_num(seq_nums: &SeqNums) -> AResult<SeqNum> {
-    let max_seq_num = if seq_nums.len() > 0 { seq_nums[0] } else { 0 };
+    let max_seq_num = if !seq_nums.is_empty() { seq_nums[0] } else { 0 };
     Ok(max_seq_num)
 }
 
@@ -1265,13 +4510,406 @@ fn calc_basis_seq_num(
 ) -> AResult<SeqNum> {
     let basis_seq_num = basis_option.unwrap_or(cur_seq_num - 1);
     if basis_seq_num > 0 && !seq_nums.contains(&basis_seq_num) {
-        bail!("basis not present for `--basis {}`", basis_seq_num);
+        bail!(StatusError::new(
+            STATUS_BASIS_UNAVAILABLE,
+            format!("basis not present for `--basis {}`", basis_seq_num)
+        ));
     }
     Ok(basis_seq_num)
 }
 
 //////////////////////////////////////////////////////////////////////////////
 
+// An outbox is a directory of ibundle files awaiting transfer, named from a
+// template and listed in a catalog file with one line per ibundle:
+//
+//   <repo_id> <seq_num> <basis_seq_num> <size> <sha256> <created> <file_name>
+//
+// Template placeholders are `{repo}` (the repository's directory name without
+// any `.git` suffix), `{repo_id}` (the first 8 characters of the repo_id), and
+// `{seq_num}` and `{basis}` (zero-padded to 4 digits).
+
+const OUTBOX_CATALOG_FORMAT_V1: &[u8] = b"# v1 git-ibundle outbox catalog";
+const OUTBOX_CATALOG_NAME: &str = "catalog";
+const DEFAULT_OUTBOX_TEMPLATE: &str = "{repo}-{seq_num}.ibundle";
+
+#[derive(Debug, Clone)]
+struct CatalogEntry {
+    repo_id: BString,
+    seq_num: SeqNum,
+    basis_seq_num: SeqNum,
+    size: u64,
+    sha256: String,
+    created: String,
+    file_name: BString,
+}
+
+fn repo_name(repo: &git2::Repository) -> String {
+    let name = repo_dir_path(repo)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match name.strip_suffix(".git") {
+        Some(stem) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    }
+}
+
+fn outbox_file_name(
+    template: &str,
+    repo_name: &str,
+    repo_id: &BStr,
+    seq_num: SeqNum,
+    basis_seq_num: SeqNum,
+) -> AResult<String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("unterminated placeholder in outbox template"),
+        };
+        match &rest[start + 1..end] {
+            "repo" => name.push_str(repo_name),
+            "repo_id" => name.push_str(
+                &repo_id.to_str_lossy().chars().take(8).collect::<String>(),
+            ),
+            "seq_num" => name.push_str(&format!("{:04}", seq_num)),
+            "basis" => name.push_str(&format!("{:04}", basis_seq_num)),
+            placeholder => bail!(
+                "unknown placeholder {{{}}} in outbox template",
+                placeholder
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.chars().any(path::is_separator)
+        || name == OUTBOX_CATALOG_NAME
+    {
+        bail!("invalid outbox file name {}", quoted(name.as_bytes()));
+    }
+    Ok(name)
+}
+
+// Formats `seconds` since 1970-01-01 as an ISO 8601 UTC time.
+fn format_utc_time(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let secs = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+fn catalog_read(outbox_path: &path::Path) -> AResult<Vec<CatalogEntry>> {
+    let catalog_path = outbox_path.join(OUTBOX_CATALOG_NAME);
+    let mut entries = Vec::new();
+    if !catalog_path.exists() {
+        return Ok(entries);
+    }
+    let mut reader = open_reader(&catalog_path)?;
+    let mut bline = BString::from("");
+    read_bline(&mut reader, &mut bline)?;
+    if bline != OUTBOX_CATALOG_FORMAT_V1 {
+        bail!(
+            "invalid outbox catalog header in {}",
+            quoted_path(&catalog_path)
+        );
+    }
+    while read_bline(&mut reader, &mut bline)? > 0 {
+        let fields = bline.splitn_str(7, b" ").collect::<Vec<_>>();
+        if fields.len() != 7 {
+            bail!("invalid outbox catalog line {}", quoted(&bline));
+        }
+        entries.push(CatalogEntry {
+            repo_id: fields[0].into(),
+            seq_num: parse_seq_num(fields[1])?,
+            basis_seq_num: parse_seq_num(fields[2])?,
+            size: fields[3].to_str_lossy().parse()?,
+            sha256: fields[4].to_str_lossy().to_string(),
+            created: fields[5].to_str_lossy().to_string(),
+            file_name: fields[6].into(),
+        });
+    }
+    Ok(entries)
+}
+
+fn catalog_write(
+    outbox_path: &path::Path,
+    entries: &[CatalogEntry],
+) -> AResult<()> {
+    let catalog_path = outbox_path.join(OUTBOX_CATALOG_NAME);
+    let temp_path = outbox_path.join(format!("{}.tmp", OUTBOX_CATALOG_NAME));
+    let mut writer = create_writer(&temp_path)?;
+    write_bline(&mut writer, OUTBOX_CATALOG_FORMAT_V1.as_bstr())?;
+    for entry in entries.iter() {
+        writeln!(
+            writer,
+            "{} {} {} {} {} {} {}",
+            entry.repo_id,
+            entry.seq_num,
+            entry.basis_seq_num,
+            entry.size,
+            entry.sha256,
+            entry.created,
+            entry.file_name
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&temp_path, &catalog_path)?;
+    Ok(())
+}
+
+// Records `ibundle` (already written to `ibundle_path` in the outbox) in the
+// outbox catalog, replacing any previous entry for the same file.
+fn catalog_add(
+    outbox_path: &path::Path,
+    ibundle: &IBundle,
+    ibundle_path: &path::Path,
+) -> AResult<()> {
+    let file_name = match ibundle_path.file_name() {
+        Some(file_name) => BString::from(file_name.to_string_lossy().as_ref()),
+        None => bail!("invalid outbox path {}", quoted_path(ibundle_path)),
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut entries = catalog_read(outbox_path)?;
+    entries.retain(|entry| entry.file_name != file_name);
+    entries.push(CatalogEntry {
+        repo_id: ibundle.repo_id.clone(),
+        seq_num: ibundle.seq_num,
+        basis_seq_num: ibundle.basis_seq_num,
+        size: fs::metadata(ibundle_path)?.len(),
+        sha256: file_sha256(ibundle_path)?,
+        created: format_utc_time(i64::try_from(now)?),
+        file_name,
+    });
+    catalog_write(outbox_path, &entries)
+}
+
+// An entry is superseded by a later ibundle (from the same repository) that
+// applies wherever this one does: one with the same basis, or with none.  An
+// earlier nonzero basis is not enough, as the destination may have cleaned
+// away its metadata while keeping this entry's basis.
+fn catalog_entry_superseded(
+    entry: &CatalogEntry,
+    entries: &[CatalogEntry],
+) -> bool {
+    entries.iter().any(|other| {
+        other.repo_id == entry.repo_id
+            && other.seq_num > entry.seq_num
+            && (other.basis_seq_num == entry.basis_seq_num
+                || other.basis_seq_num == 0)
+    })
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+// A multi-repository archive is a table of contents followed by the contained
+// ibundle files, each followed by its approvals (if any), in table order:
+//
+//   # v1 git-ibundle archive
+//   <repo_id> <seq_num> <basis_seq_num> <size> <approvals_size> <sha256> <name>
+//   ...
+//   <blank line>
+//   <ibundle data><approvals data>...
+const ARCHIVE_FORMAT_V1: &[u8] = b"# v1 git-ibundle archive";
+
+#[derive(Debug, Clone)]
+struct ArchiveEntry {
+    repo_id: BString,
+    seq_num: SeqNum,
+    basis_seq_num: SeqNum,
+    size: u64,
+    approvals_size: u64,
+    sha256: String,
+    name: BString,
+    // Position of the ibundle data within the archive file.
+    offset: u64,
+}
+
+fn archive_read(
+    archive_path: &path::Path,
+) -> AResult<(Vec<ArchiveEntry>, io::BufReader<fs::File>)> {
+    let mut reader = open_reader(archive_path)?;
+    let mut bline = BString::from("");
+    read_bline(&mut reader, &mut bline)?;
+    if bline != ARCHIVE_FORMAT_V1 {
+        bail!("not a V1 archive file: {}", quoted_path(archive_path));
+    }
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while read_bline(&mut reader, &mut bline)? > 0 {
+        let fields = bline.splitn_str(7, b" ").collect::<Vec<_>>();
+        if fields.len() != 7 {
+            bail!("invalid archive table line {}", quoted(&bline));
+        }
+        let size = fields[3].to_str_lossy().parse()?;
+        let approvals_size = fields[4].to_str_lossy().parse()?;
+        entries.push(ArchiveEntry {
+            repo_id: fields[0].into(),
+            seq_num: parse_seq_num(fields[1])?,
+            basis_seq_num: parse_seq_num(fields[2])?,
+            size,
+            approvals_size,
+            sha256: fields[5].to_str_lossy().to_string(),
+            name: fields[6].into(),
+            offset,
+        });
+        offset = size
+            .checked_add(approvals_size)
+            .and_then(|len| offset.checked_add(len))
+            .with_context(|| {
+                format!("corrupt archive {}", quoted_path(archive_path))
+            })?;
+    }
+    let data_start = reader.stream_position()?;
+    if offset.checked_add(data_start).is_none() {
+        bail!("corrupt archive {}", quoted_path(archive_path));
+    }
+    for entry in entries.iter_mut() {
+        entry.offset += data_start;
+    }
+    Ok((entries, reader))
+}
+
+// Copies the ibundle for `entry` out of the archive into `ibundle_path`, and
+// any approvals next to it.
+fn archive_extract(
+    archive_reader: &mut io::BufReader<fs::File>,
+    entry: &ArchiveEntry,
+    ibundle_path: &path::Path,
+) -> AResult<()> {
+    archive_reader.seek(io::SeekFrom::Start(entry.offset))?;
+    let mut writer = create_writer(ibundle_path)?;
+    let size = io::copy(&mut archive_reader.take(entry.size), &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    if size != entry.size {
+        bail!("archive truncated in {}", quoted(&entry.name));
+    }
+    if file_sha256(ibundle_path)? != entry.sha256 {
+        bail!("archive checksum mismatch for {}", quoted(&entry.name));
+    }
+    if entry.approvals_size > 0 {
+        let mut writer = create_writer(approvals_path(ibundle_path))?;
+        let size = io::copy(
+            &mut archive_reader.take(entry.approvals_size),
+            &mut writer,
+        )?;
+        writer.flush()?;
+        if size != entry.approvals_size {
+            bail!("archive truncated in approvals of {}", quoted(&entry.name));
+        }
+    }
+    Ok(())
+}
+
+fn json_archive_entries(entries: &[ArchiveEntry]) -> serde_json::Value {
+    entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": json_bstr(entry.name.as_bstr()),
+                "repo_id": json_bstr(entry.repo_id.as_bstr()),
+                "seq_num": entry.seq_num,
+                "basis_seq_num": entry.basis_seq_num,
+                "size": entry.size,
+                "approvals_size": entry.approvals_size,
+                "sha256": entry.sha256,
+            })
+        })
+        .collect()
+}
+
+// Reads a repo map file of `<repo_id> <path>` lines (with `#` comments);
+// relative paths are relative to the map file's directory.
+fn repo_map_read(
+    repo_map_path: &path::Path,
+) -> AResult<collections::BTreeMap<BString, path::PathBuf>> {
+    let base_path = repo_map_path.parent().unwrap_or(path::Path::new(""));
+    let mut reader = open_reader(repo_map_path)?;
+    let mut repo_map = collections::BTreeMap::new();
+    let mut bline = BString::from("");
+    loop {
+        bline.clear();
+        if reader.read_until(b'\n', &mut bline)? == 0 {
+            break;
+        }
+        let line = bline.trim();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let (repo_id, rest) = bstr_pop_word(line.as_bstr());
+        let rest = rest.trim();
+        if rest.is_empty() {
+            bail!(
+                "invalid line {} in repo map {}",
+                quoted(line),
+                quoted_path(repo_map_path)
+            );
+        }
+        repo_map.insert(repo_id.into(), base_path.join(rest.to_path()?));
+    }
+    Ok(repo_map)
+}
+
+// Maps repo_ids to repository paths, from the repositories directly within
+// `repos_dir_path` and then from any repo map (which takes precedence).
+fn archive_repo_paths(
+    repos_dir_path: &path::Path,
+    repo_map_path: Option<&path::Path>,
+) -> AResult<collections::BTreeMap<BString, path::PathBuf>> {
+    let mut repo_paths = collections::BTreeMap::new();
+    let mut dir_paths = fs::read_dir(repos_dir_path)
+        .with_context(|| {
+            format!(
+                "failed to read repositories directory {}",
+                quoted_path(repos_dir_path)
+            )
+        })?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<AResult<Vec<_>>>()?;
+    dir_paths.sort();
+    for dir_path in dir_paths {
+        let repo_id = match git2::Repository::open(&dir_path) {
+            Ok(repo) => repo_id_read(&repo),
+            Err(_) => None,
+        };
+        if let Some(repo_id) = repo_id {
+            if let Some(other_path) = repo_paths.get(&repo_id) {
+                log::warn!(
+                    "ignoring {} with the same repo_id as {}",
+                    quoted_path(&dir_path),
+                    quoted_path(other_path)
+                );
+                continue;
+            }
+            repo_paths.insert(repo_id, dir_path);
+        }
+    }
+    if let Some(repo_map_path) = repo_map_path {
+        repo_paths.extend(repo_map_read(repo_map_path).with_context(|| {
+            format!("failure reading repo map {}", quoted_path(repo_map_path))
+        })?);
+    }
+    Ok(repo_paths)
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
 fn read_ibundle<P: AsRef<std::path::Path>>(
     ibundle_path: P,
 ) -> AResult<(IBundle, io::BufReader<fs::File>)> {
@@ -1284,9 +4922,15 @@ fn read_ibundle<P: AsRef<std::path::Path>>(
     Ok((ibundle, ibundle_reader))
 }
 
-fn cmd_create(create_args: &CreateArgs) -> AResult<i32> {
-    let repo_path = ".";
+fn cmd_create(
+    create_args: &CreateArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
     let repo = repo_open(repo_path)?;
+    let standalone = create_args.standalone
+        || (!create_args.no_standalone
+            && repo_config_bool(&repo, "ibundle.standalone")?.unwrap_or(false));
     let repo_id = if let Some(repo_id) = repo_id_read(&repo) {
         repo_id
     } else {
@@ -1297,7 +4941,10 @@ fn cmd_create(create_args: &CreateArgs) -> AResult<i32> {
 
     let seq_nums = repo_seq_nums(&repo)?;
     let seq_num = calc_next_seq_num(&seq_nums)?;
-    let meta = repo_meta_current(&repo)?;
+    let meta = repo_meta_current_filtered(
+        &repo,
+        &RefFilter::new(&create_args.include_refs, &create_args.exclude_refs),
+    )?;
 
     let basis_seq_num;
     let basis_meta;
@@ -1314,6 +4961,37 @@ fn cmd_create(create_args: &CreateArgs) -> AResult<i32> {
         };
     }
 
+    let outbox_path = match &create_args.outbox {
+        Some(outbox_path) => Some(outbox_path.clone()),
+        None if create_args.ibundle_path.is_none() => {
+            repo_config_path(&repo, "ibundle.outbox")?
+        }
+        None => None,
+    };
+    let ibundle_file_name = || -> AResult<String> {
+        let template = repo_config_string(&repo, "ibundle.outboxTemplate")?
+            .unwrap_or_else(|| DEFAULT_OUTBOX_TEMPLATE.to_string());
+        outbox_file_name(
+            &template,
+            &repo_name(&repo),
+            repo_id.as_bstr(),
+            seq_num,
+            basis_seq_num,
+        )
+    };
+    let ibundle_path = match (&create_args.ibundle_path, &outbox_path) {
+        (_, Some(outbox_path)) => outbox_path.join(ibundle_file_name()?),
+        (Some(ibundle_path), None) => ibundle_path.clone(),
+        // Like an outbox, but without a catalog.
+        (None, None) => match repo_config_path(&repo, "ibundle.outputDir")? {
+            Some(output_dir_path) => output_dir_path.join(ibundle_file_name()?),
+            None => bail!(
+                "missing <IBUNDLE_FILE>; use `--outbox`, `ibundle.outbox`, \
+                or `ibundle.outputDir`"
+            ),
+        },
+    };
+
     let mut ibundle = IBundle::construct(
         repo_id,
         seq_num,
@@ -1329,9 +5007,68 @@ fn cmd_create(create_args: &CreateArgs) -> AResult<i32> {
                 "consider `--allow-empty`"
             ));
         }
+        report.message = Some("refusing to create an empty ibundle".into());
         return Ok(STATUS_EMPTY_BUNDLE);
     }
 
+    if !create_args.allow_mass_changes {
+        let mut violations = Vec::new();
+        if let Some(max_removed_percent) =
+            repo_config_u64(&repo, "ibundle.maxRemovedPercent")?
+        {
+            violations.extend(removed_refs_violation(
+                count_removed_refs(&basis_meta.orefs, &meta.orefs),
+                count_removed_refs(&basis_meta.orefs, &ORefs::new()),
+                max_removed_percent,
+            ));
+        }
+        if let Some(max_forced_refs) =
+            repo_config_u64(&repo, "ibundle.maxForcedRefs")?
+        {
+            violations.extend(forced_refs_violation(
+                forced_refs(&repo, &basis_meta.orefs, &meta.orefs).len(),
+                max_forced_refs,
+            ));
+        }
+        if !violations.is_empty() {
+            if log_enabled!(Level::Error) {
+                for violation in violations.iter() {
+                    eprintln!("ibundle {}", violation);
+                }
+                eprintln!(std::concat!(
+                    "error: refusing to create ibundle with mass changes; ",
+                    "consider `--allow-mass-changes`"
+                ));
+            }
+            report.set(
+                "violations",
+                violations
+                    .iter()
+                    .map(|violation| format!("ibundle {}", violation))
+                    .collect::<Vec<_>>(),
+            );
+            report.message = Some(
+                "refusing to create ibundle with mass changes".to_string(),
+            );
+            return Ok(STATUS_MASS_CHANGE);
+        }
+    }
+
+    let ref_updates = ref_update_lines(&basis_meta.orefs, &meta.orefs)?;
+    let mut hook_env = ibundle.hook_env();
+    hook_env.push(("IBUNDLE_FILE", ibundle_path.clone().into()));
+    hook_env.push((
+        "IBUNDLE_STANDALONE",
+        bool_as_bstr(standalone).to_string().into(),
+    ));
+    if let Some(exit_status) =
+        repo_run_hook(&repo, "pre-create", &hook_env, &ref_updates)?
+    {
+        if !exit_status.success() {
+            bail!("pre-create hook refused ibundle ({})", exit_status);
+        }
+    }
+
     // OIDs for still-valid commits and refs are fair game to exclude.
     let excluded_oids = basis_meta
         .commits
@@ -1340,97 +5077,178 @@ fn cmd_create(create_args: &CreateArgs) -> AResult<i32> {
         .filter(|oid| repo_has_oid(&repo, **oid))
         .collect::<collections::HashSet<_>>();
 
-    let bundle_orefs = if create_args.standalone {
+    let bundle_orefs = if standalone {
         ibundle.full_orefs()?
     } else {
         ibundle.delta_orefs()?
     };
 
-    let temp_dir_path = repo_mktemp(&repo)?;
-    let bundle_path = temp_dir_path.join("temp.bundle");
-    let bundle_path_deleter = FileDeleter::new(&bundle_path);
-
-    let stdin_path = temp_dir_path.join("temp.stdin");
-    let stdin_path_deleter = FileDeleter::new(&stdin_path);
-
-    let mut stdin_file = fs::File::create(&stdin_path)?;
-    for oid in excluded_oids.iter() {
-        stdin_file.write_all(b"^")?;
-        stdin_file.write_all(oid_to_bstring(oid).as_bstr())?;
-        stdin_file.write_all(b"\n")?;
-    }
-    for (name, _oid) in bundle_orefs.iter() {
-        write_bline(&mut stdin_file, name.as_bstr())?;
-    }
-    stdin_file.flush()?;
-    drop(stdin_file);
-
-    git_bundle_create_stdin(&bundle_path, open_file(&stdin_path)?)?;
-    drop(stdin_path_deleter);
-
-    let mut bundle_reader = open_reader(&bundle_path)?;
-    let (mut prereqs, packed_orefs) =
-        git_bundle_header_read(&mut bundle_reader)?;
-
-    for (name, &oid) in bundle_orefs.iter() {
-        if !packed_orefs.contains_key(name) {
-            // Git thinks we don't need this `oref` because the associated
-            // object (tag or commit) was excluded by the basis.  We want it
-            // anyway, so add the associated commit to the `prereqs`.
-            if let Ok(obj) = repo.find_object(oid, None) {
-                if let Ok(commit) = obj.peel_to_commit() {
-                    let commit_id = commit.id();
-                    if !prereqs.contains_key(&commit_id) {
-                        prereqs.insert(commit_id, commit_comment(&commit));
+    if let Some(policy_path) = &create_args.policy {
+        let policy = Policy::read(&mut open_reader(policy_path)?)
+            .with_context(|| {
+                format!("failure reading policy {}", quoted_path(policy_path))
+            })?;
+        let labeled_violations = repo_policy_violations(
+            &repo,
+            &policy,
+            &bundle_orefs,
+            &excluded_oids,
+        )?;
+        if !labeled_violations.is_empty() {
+            if log_enabled!(Level::Error) {
+                for (label, violations) in labeled_violations.iter() {
+                    eprintln!("{}", label);
+                    for violation in violations.iter() {
+                        eprintln!("  {}", violation);
                     }
                 }
             }
+            let num_commits = labeled_violations
+                .iter()
+                .filter(|(label, _)| label.starts_with("commit "))
+                .count();
+            let num_refs = labeled_violations.len() - num_commits;
+            bail!(
+                "policy violations in {} commits{}; refusing to create ibundle",
+                num_commits,
+                if num_refs > 0 {
+                    format!(" and {} refs", num_refs)
+                } else {
+                    String::new()
+                }
+            );
         }
     }
 
+    let PackPlan {
+        prereqs,
+        packed_orefs,
+        write: write_pack,
+    } = backend().plan_pack(&repo, &bundle_orefs, &excluded_oids)?;
     ibundle.prereqs = prereqs;
     ibundle.packed_orefs = packed_orefs;
 
-    let mut ibundle_writer = create_writer(&create_args.ibundle_path)?;
-    ibundle.write(&mut ibundle_writer, create_args.standalone)?;
-    io::copy(&mut bundle_reader, &mut ibundle_writer)?;
-    drop(bundle_reader);
-    drop(bundle_path_deleter);
+    if let Some(outbox_path) = &outbox_path {
+        fs::create_dir_all(outbox_path)?;
+    }
+    let mut ibundle_writer = create_writer(&ibundle_path)?;
+    ibundle.write(&mut ibundle_writer, standalone)?;
+    write_pack(&mut ibundle_writer)?;
     ibundle_writer.flush()?;
     drop(ibundle_writer);
 
+    if let Some(manifest_path) = &create_args.manifest {
+        let mut manifest_writer = create_writer(manifest_path)?;
+        manifest_write(
+            &mut manifest_writer,
+            &repo,
+            &ibundle,
+            &ibundle_path,
+            standalone,
+            Some(&basis_meta.orefs),
+            excluded_oids.iter().copied(),
+        )?;
+        manifest_writer.flush()?;
+        log::info!("wrote manifest {}", quoted_path(manifest_path));
+    }
+
     repo_meta_write(&repo, seq_num, &meta)?;
     log::info!(
         "wrote {}: {}",
-        quoted_path(&create_args.ibundle_path),
+        quoted_path(&ibundle_path),
         ibundle.summary()
     );
+    if let Some(outbox_path) = &outbox_path {
+        catalog_add(outbox_path, &ibundle, &ibundle_path)?;
+        log::info!(
+            "cataloged in {}",
+            quoted_path(outbox_path.join(OUTBOX_CATALOG_NAME))
+        );
+    }
+    let changes = ibundle_ref_changes(&repo, &ibundle, Some(&basis_meta.orefs));
+    report_ref_changes(&changes, create_args.porcelain && !report.is_json());
+    report.set("ibundle_path", ibundle_path.to_string_lossy().as_ref());
+    report.set("seq_num", ibundle.seq_num);
+    report.set("basis_seq_num", ibundle.basis_seq_num);
+    report.set("standalone", standalone);
+    report.set("num_added", ibundle.added_orefs.len());
+    report.set("num_removed", ibundle.removed_orefs.len());
+    report.set("num_moved", ibundle.moved_orefs.len());
+    report.set(
+        "num_unchanged",
+        ibundle.unchanged_orefs.as_ref().map(|orefs| orefs.len()),
+    );
+    report.set("num_prereqs", ibundle.prereqs.len());
+    report.set("ref_updates", json_ref_changes(&changes));
+
+    hook_env.push(("IBUNDLE_SUMMARY", ibundle.summary().into()));
+    if let Some(exit_status) =
+        repo_run_hook(&repo, "post-create", &hook_env, &ref_updates)?
+    {
+        if !exit_status.success() {
+            log::warn!("post-create hook failed ({})", exit_status);
+        }
+    }
     Ok(STATUS_OK)
 }
 
-fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
+fn cmd_fetch(
+    fetch_args: &FetchArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
+    if fetch_args.archive {
+        return cmd_fetch_archive(fetch_args, repo_path, report);
+    }
+
     if fetch_args.dry_run {
         log::info!("(dry run)");
     }
 
-    let repo_path = ".";
     let repo = repo_open(repo_path)?;
 
     if !repo.is_bare() {
         bail!("cannot fetch into non-bare repository");
     }
 
+    let fsck_objects = fetch_args.checks.fsck_objects
+        || repo_config_bool(&repo, "ibundle.fsckObjects")?.unwrap_or(false);
+    let archive_refs = fetch_args.checks.archive_refs
+        || repo_config_bool(&repo, "ibundle.archiveRefs")?.unwrap_or(false);
+    let verify_signatures = fetch_args.checks.verify_signatures
+        || repo_config_bool(&repo, "ibundle.verifySignatures")?
+            .unwrap_or(false);
+    let identity_policy_path = match &fetch_args.checks.identity_policy {
+        Some(path) => Some(path.clone()),
+        None => repo_config_path(&repo, "ibundle.identityPolicy")?,
+    };
+    let identity_policy = match identity_policy_path {
+        Some(path) => {
+            Some(IdentityPolicy::read(&mut open_reader(&path)?).with_context(
+                || {
+                    format!(
+                        "failure reading identity policy {}",
+                        quoted_path(&path)
+                    )
+                },
+            )?)
+        }
+        None => None,
+    };
+
     let ibundle_path = &fetch_args.ibundle_path;
-    let (mut ibundle, ibundle_reader) = read_ibundle(ibundle_path)?;
+    let (mut ibundle, mut ibundle_reader) = read_ibundle(ibundle_path)?;
 
     ibundle.validate_and_apply_basis(&repo, fetch_args.force)?;
 
-    log::info!("read {}: {}", quoted_path(&ibundle_path), ibundle.summary());
+    log::info!("read {}: {}", quoted_path(ibundle_path), ibundle.summary());
+
+    repo_check_approvals(&repo, ibundle_path, &mut ibundle_reader)?;
 
     let mut ready_for_ibundle = true;
 
     let missing_prereqs = repo_find_missing_commits(&repo, &ibundle.prereqs);
-    if missing_prereqs.len() > 0 {
+    if !missing_prereqs.is_empty() {
         ready_for_ibundle = false;
         if log_enabled!(Level::Error) {
             eprintln!(
@@ -1447,7 +5265,15 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
         }
     }
 
-    let full_orefs = ibundle.full_orefs()?;
+    // Refs matching protected patterns are local to the repository.
+    let pre_meta = repo_meta_current(&repo)?;
+    let protected_patterns =
+        repo_config_multivar(&repo, "ibundle.protectedRefs")?;
+    let full_orefs = protect_orefs(
+        ibundle.full_orefs()?,
+        &pre_meta.orefs,
+        &protected_patterns,
+    );
 
     // OIDs not being created by the pack must pre-exist.
     let missing_orefs = full_orefs
@@ -1457,7 +5283,7 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
                 && !repo_has_oid(&repo, **oid)
         })
         .collect_orefs();
-    if missing_orefs.len() > 0 {
+    if !missing_orefs.is_empty() {
         ready_for_ibundle = false;
         if log_enabled!(Level::Error) {
             eprintln!(
@@ -1476,21 +5302,23 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
     }
 
     if !ready_for_ibundle {
-        bail!(
-            "repo not ready for ibundle with basis_seq_num {}",
-            ibundle.basis_seq_num
-        );
-    }
-
-    if !fetch_args.dry_run {
-        repo_id_write(&repo, ibundle.repo_id.as_bstr())?;
+        bail!(StatusError::new(
+            if missing_prereqs.is_empty() {
+                STATUS_MISSING_OREFS
+            } else {
+                STATUS_MISSING_PREREQS
+            },
+            format!(
+                "repo not ready for ibundle with basis_seq_num {}",
+                ibundle.basis_seq_num
+            )
+        ));
     }
 
-    let pre_meta = repo_meta_current(&repo)?;
-    let mut refs_to_remove = pre_meta
+    let refs_to_remove = pre_meta
         .orefs
-        .iter()
-        .filter_map(|(name, _oid)| {
+        .keys()
+        .filter_map(|name| {
             if name != b"HEAD".as_bstr() && !full_orefs.contains_key(name) {
                 Some(name.clone())
             } else {
@@ -1499,53 +5327,136 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
         })
         .collect::<collections::HashSet<_>>();
 
-    let mut bundle_orefs = full_orefs
+    // The whole pack is stored, so a detached HEAD needs no ref of its own.
+    let bundle_orefs = full_orefs
         .iter()
         .filter(|(name, oid)| {
             *name != b"HEAD".as_bstr() && pre_meta.orefs.get(*name) != Some(oid)
         })
         .collect_orefs();
 
-    if let Some(&head_oid) = ibundle.packed_orefs.get(b"HEAD".as_bstr()) {
-        let packed_oids = ibundle
-            .packed_orefs
-            .iter()
-            .filter_map(|(name, &oid)| {
-                if name != b"HEAD".as_bstr() {
-                    Some(oid)
-                } else {
-                    None
-                }
-            })
-            .collect::<collections::HashSet<_>>();
-        if !packed_oids.contains(&head_oid) {
-            let mut h = BString::from("refs/heads/HEAD-");
-            h.push_str(oid_to_bstring(&head_oid));
-            bundle_orefs.insert(h.clone(), head_oid);
-            refs_to_remove.insert(h);
+    let max_forced_refs = if fetch_args.checks.allow_mass_changes {
+        None
+    } else {
+        if let Some(max_removed_percent) =
+            repo_config_u64(&repo, "ibundle.maxRemovedPercent")?
+        {
+            if let Some(violation) = removed_refs_violation(
+                count_removed_refs(&pre_meta.orefs, &full_orefs),
+                count_removed_refs(&pre_meta.orefs, &ORefs::new()),
+                max_removed_percent,
+            ) {
+                bail!(StatusError::new(
+                    STATUS_MASS_CHANGE,
+                    format!(
+                        "ibundle {}; refusing to fetch \
+                        (consider `--allow-mass-changes`)",
+                        violation
+                    )
+                ));
+            }
+        }
+        repo_config_u64(&repo, "ibundle.maxForcedRefs")?
+    };
+
+    let ref_updates = ref_update_lines(&pre_meta.orefs, &full_orefs)?;
+    let mut hook_env = ibundle.hook_env();
+    hook_env.push(("IBUNDLE_FILE", ibundle_path.into()));
+    hook_env.push((
+        "IBUNDLE_DRY_RUN",
+        bool_as_bstr(fetch_args.dry_run).to_string().into(),
+    ));
+    if let Some(exit_status) =
+        repo_run_hook(&repo, "pre-fetch", &hook_env, &ref_updates)?
+    {
+        if !exit_status.success() {
+            bail!("pre-fetch hook rejected ibundle ({})", exit_status);
         }
     }
 
-    repo_fetch(
-        &repo,
-        &ibundle.prereqs,
-        &bundle_orefs,
-        ibundle_reader,
-        fetch_args.dry_run,
-    )?;
+    let checks = PackChecks {
+        old_orefs: &pre_meta.orefs,
+        new_orefs: &full_orefs,
+        prereqs: &ibundle.prereqs,
+        fsck_objects,
+        verify_signatures,
+        allowed_signers_path: repo_config_path(
+            &repo,
+            "ibundle.allowedSignersFile",
+        )?,
+        identity_policy,
+        max_forced_refs,
+        fast_forward_only: repo_config_multivar(
+            &repo,
+            "ibundle.fastForwardOnly",
+        )?,
+    };
 
-    let head_ref = ibundle.head_ref.as_bstr();
-    if !fetch_args.dry_run && head_ref != "" {
-        if ibundle.head_detached {
-            let commit_id = parse_oid(head_ref)?;
-            repo.set_head_detached(commit_id)?;
-        } else {
-            repo_set_head_ref(&repo, head_ref)?;
-        }
+    // The pack is read from the same (already hashed) handle, unbuffered so
+    // that a backend may seek within it.
+    let pack_offset = ibundle_reader.stream_position()?;
+    let mut pack_file = ibundle_reader.into_inner();
+    pack_file.seek(io::SeekFrom::Start(pack_offset))?;
+    repo_fetch(&repo, &bundle_orefs, pack_file, fetch_args.dry_run, &checks)?;
+
+    // Only an ibundle that passed its checks claims the repository.
+    if !fetch_args.dry_run {
+        repo_id_write(&repo, ibundle.repo_id.as_bstr())?;
+    }
+
+    // Archive every ref that will be removed or moved non-fast-forward; the
+    // pack's objects are now present for classifying each move.
+    let mut updates = Vec::new();
+    if archive_refs && !fetch_args.dry_run {
+        let refs_to_archive = pre_meta
+            .orefs
+            .keys()
+            .filter(|name| {
+                let old_oid = pre_meta.orefs.get(*name).copied();
+                let new_oid = full_orefs.get(*name).copied();
+                *name != b"HEAD".as_bstr()
+                    && new_oid != old_oid
+                    && RefChange::classify(&repo, old_oid, new_oid)
+                        != RefChange::FastForward
+            })
+            .collect::<Vec<_>>();
+        updates = archive_ref_updates(
+            ibundle.seq_num,
+            &pre_meta.orefs,
+            refs_to_archive.iter().copied(),
+        );
     }
+    let num_archived = updates.len();
 
+    // Archive refs, updated refs, and removed refs all change in one
+    // transaction; HEAD follows only once that has succeeded.
+    let head_ref = ibundle.head_ref.as_bstr();
     if !fetch_args.dry_run {
-        repo_remove_refs(&repo, &refs_to_remove)?;
+        updates.extend(
+            bundle_orefs
+                .iter()
+                .map(|(name, &oid)| (name.clone(), Some(oid))),
+        );
+        updates.extend(refs_to_remove.iter().map(|name| (name.clone(), None)));
+        backend().update_refs(&repo, &updates, "git-ibundle: fetch")?;
+
+        if num_archived > 0 {
+            log::info!(
+                "archived {} removed or force-moved refs under {}{}/",
+                num_archived,
+                ARCHIVE_REF_PREFIX.as_bstr(),
+                ibundle.seq_num
+            );
+        }
+
+        if !head_ref.is_empty() {
+            if ibundle.head_detached {
+                let commit_id = parse_oid(head_ref)?;
+                repo.set_head_detached(commit_id)?;
+            } else {
+                repo_set_head_ref(&repo, head_ref)?;
+            }
+        }
     }
 
     let post_meta = if fetch_args.dry_run {
@@ -1583,9 +5494,39 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
     }
 
     if !fetch_args.dry_run {
-        repo_meta_write(&repo, ibundle.seq_num, &post_meta)?;
+        // Protected refs are local, so they stay out of the metadata that
+        // later ibundles use as their basis.
+        let mut meta = post_meta.clone();
+        meta.orefs.retain(|name, _| {
+            !is_protected_ref(name.as_bstr(), &protected_patterns)
+        });
+        meta.commits = meta
+            .orefs
+            .values()
+            .filter_map(|&oid| repo_commit_id_comment(&repo, oid).ok())
+            .collect();
+        repo_meta_write(&repo, ibundle.seq_num, &meta)?;
+        if let Some(exit_status) =
+            repo_run_hook(&repo, "post-fetch", &hook_env, &ref_updates)?
+        {
+            if !exit_status.success() {
+                log::warn!("post-fetch hook failed ({})", exit_status);
+            }
+        }
     }
 
+    let changes = orefs_ref_changes(&repo, &pre_meta.orefs, &full_orefs);
+    report_ref_changes(&changes, fetch_args.porcelain && !report.is_json());
+    report.set("ibundle_path", ibundle_path.to_string_lossy().as_ref());
+    report.set("dry_run", fetch_args.dry_run);
+    report.set("seq_num", ibundle.seq_num);
+    report.set("basis_seq_num", ibundle.basis_seq_num);
+    report.set("ref_updates", json_ref_changes(&changes));
+    report.set("num_archived", num_archived);
+    report.set("num_refs", post_meta.orefs.len());
+    report.set("head_ref", json_bstr(post_meta.head_ref.as_bstr()));
+    report.set("head_detached", post_meta.head_detached);
+
     log::info!(
         "final state: {} refs, HEAD {}{}",
         post_meta.orefs.len(),
@@ -1599,14 +5540,137 @@ fn cmd_fetch(fetch_args: &FetchArgs) -> AResult<i32> {
     Ok(STATUS_OK)
 }
 
+// Runs `cmd_fetch`, returning its exit status and any error message rather
+// than failing.
+fn fetch_capture(
+    fetch_args: &FetchArgs,
+    repo_path: &path::Path,
+) -> (i32, String) {
+    let mut fetch_report = Report::new(OutputFormat::Text, "fetch");
+    match cmd_fetch(fetch_args, repo_path, &mut fetch_report) {
+        Ok(status) => (status, fetch_report.message.unwrap_or_default()),
+        Err(e) => {
+            if log_enabled!(Level::Error) {
+                eprintln!("error: {:?}", e);
+            }
+            (error_status(&e), format!("{:#}", e))
+        }
+    }
+}
+
+// Extracts `entry` into the temporary directory of the repository at
+// `repo_path` and fetches it from there.
+fn archive_fetch_entry(
+    fetch_args: &FetchArgs,
+    archive_reader: &mut io::BufReader<fs::File>,
+    entry: &ArchiveEntry,
+    repo_path: &path::Path,
+) -> AResult<(i32, String)> {
+    let ibundle_path =
+        repo_mktemp(&repo_open(repo_path)?)?.join("archive.ibundle");
+    let _ibundle_deleter = FileDeleter::new(&ibundle_path);
+    let _approvals_deleter = FileDeleter::new(approvals_path(&ibundle_path));
+    archive_extract(archive_reader, entry, &ibundle_path)?;
+    let entry_fetch_args = FetchArgs {
+        ibundle_path,
+        archive: false,
+        repos_dir: None,
+        repo_map: None,
+        ..fetch_args.clone()
+    };
+    Ok(fetch_capture(&entry_fetch_args, repo_path))
+}
+
+fn cmd_fetch_archive(
+    fetch_args: &FetchArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
+    if fetch_args.dry_run {
+        log::info!("(dry run)");
+    }
+    let archive_path = &fetch_args.ibundle_path;
+    let (mut entries, mut archive_reader) = archive_read(archive_path)?;
+    let repos_dir_path = fetch_args.repos_dir.as_deref().unwrap_or(repo_path);
+    let repo_paths =
+        archive_repo_paths(repos_dir_path, fetch_args.repo_map.as_deref())?;
+
+    // Each repository's ibundles are fetched in `seq_num` order.
+    entries.sort_by_key(|entry| entry.seq_num);
+    let mut results = Vec::new();
+    let mut num_failed = 0;
+    for entry in entries.iter() {
+        let entry_repo_path = repo_paths.get(&entry.repo_id);
+        let (status, message) = match entry_repo_path {
+            Some(entry_repo_path) => {
+                log::info!(
+                    "fetching {} (seq_num {}) into {}",
+                    quoted(&entry.name),
+                    entry.seq_num,
+                    quoted_path(entry_repo_path)
+                );
+                archive_fetch_entry(
+                    fetch_args,
+                    &mut archive_reader,
+                    entry,
+                    entry_repo_path,
+                )
+                .unwrap_or_else(|e| (error_status(&e), format!("{:#}", e)))
+            }
+            None => (
+                STATUS_ERROR,
+                format!("no repository found for repo_id {}", entry.repo_id),
+            ),
+        };
+        let error = if status == STATUS_OK {
+            serde_json::Value::Null
+        } else {
+            num_failed += 1;
+            log::warn!(
+                "failed to fetch {} (repo_id {}): {}",
+                quoted(&entry.name),
+                entry.repo_id,
+                message
+            );
+            serde_json::json!({
+                "code": status_code(status),
+                "status": status,
+                "message": message,
+            })
+        };
+        results.push(serde_json::json!({
+            "name": json_bstr(entry.name.as_bstr()),
+            "repo_id": json_bstr(entry.repo_id.as_bstr()),
+            "seq_num": entry.seq_num,
+            "repo": entry_repo_path.map(|path| path.to_string_lossy()),
+            "ok": status == STATUS_OK,
+            "error": error,
+        }));
+    }
+    log::info!(
+        "fetched {} of {} ibundles",
+        entries.len() - num_failed,
+        entries.len()
+    );
+    report.set("archive_path", archive_path.to_string_lossy());
+    report.set("dry_run", fetch_args.dry_run);
+    report.set("ibundles", results);
+    if num_failed > 0 {
+        report.message =
+            Some(format!("failed to fetch {} ibundles", num_failed));
+        return Ok(STATUS_ERROR);
+    }
+    Ok(STATUS_OK)
+}
+
 fn yes_no(predicate: bool) -> String {
-    format!("{}", if predicate { "yes" } else { "no" })
+    (if predicate { "yes" } else { "no" }).to_string()
 }
 
 fn show_orefs(orefs: &ORefs) {
     if log_enabled!(Level::Debug) {
         for (name, oid) in orefs {
-            log::debug!("{} {}", oid_to_bstring(oid).to_string(), quoted(name));
+            log::debug!("{} {}", oid_to_bstring(oid), quoted(name));
         }
         log::debug!(".");
     }
@@ -1615,20 +5679,75 @@ fn show_orefs(orefs: &ORefs) {
 fn show_commits(commits: &Commits) {
     if log_enabled!(Level::Debug) {
         for (oid, comment) in commits {
-            log::debug!(
-                "{} {}",
-                oid_to_bstring(oid).to_string(),
-                quoted(comment)
-            );
+            log::debug!("{} {}", oid_to_bstring(oid), quoted(comment));
         }
         log::debug!(".");
     }
 }
 
-fn cmd_show(show_args: &ShowArgs) -> AResult<i32> {
+// Writes a manifest for an existing ibundle.  The current repository must
+// contain the ibundle's prerequisites (e.g., the source repository or a
+// destination synchronized to the basis); the pack is unpacked into a
+// quarantine repository to examine the transferred commits.
+fn show_manifest(
+    repo_path: &path::Path,
+    ibundle: &IBundle,
+    ibundle_path: &path::Path,
+    pack_offset: u64,
+    manifest_path: &path::Path,
+) -> AResult<()> {
+    let repo = repo_open(repo_path)?;
+    let mut pack_file = open_file(ibundle_path)?;
+    pack_file.seek(io::SeekFrom::Start(pack_offset))?;
+    let (quarantine_path, quarantine_path_deleter) =
+        repo_quarantine_pack(&repo, "manifest.git", pack_file).context(
+            "cannot unpack ibundle; repo must contain the ibundle's prereqs",
+        )?;
+    let quarantine = repo_open(&quarantine_path)?;
+
+    let old_orefs = if ibundle.basis_seq_num == 0 {
+        Some(ORefs::new())
+    } else if repo_has_basis(&repo, &ibundle.basis_seq_num) {
+        Some(repo_meta_read(&repo, ibundle.basis_seq_num)?.orefs)
+    } else {
+        None
+    };
+
+    let mut manifest_writer = create_writer(manifest_path)?;
+    manifest_write(
+        &mut manifest_writer,
+        &quarantine,
+        ibundle,
+        ibundle_path,
+        ibundle.unchanged_orefs.is_some(),
+        old_orefs.as_ref(),
+        ibundle.prereqs.keys(),
+    )?;
+    manifest_writer.flush()?;
+    drop(quarantine);
+    drop(quarantine_path_deleter);
+    log::info!("wrote manifest {}", quoted_path(manifest_path));
+    Ok(())
+}
+
+fn cmd_show(
+    show_args: &ShowArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
     let ibundle_path = &show_args.ibundle_path;
-    let (ibundle, ibundle_reader) = read_ibundle(ibundle_path)?;
+    let (ibundle, mut ibundle_reader) = read_ibundle(ibundle_path)?;
+    let pack_offset = ibundle_reader.stream_position()?;
     drop(ibundle_reader);
+    if let Some(manifest_path) = &show_args.manifest {
+        show_manifest(
+            repo_path,
+            &ibundle,
+            ibundle_path,
+            pack_offset,
+            manifest_path,
+        )?;
+    }
     log::info!("standalone: {}", yes_no(ibundle.unchanged_orefs.is_some()));
     log::info!("repo_id: {}", ibundle.repo_id);
     log::info!("seq_num: {}", ibundle.seq_num);
@@ -1643,70 +5762,202 @@ fn cmd_show(show_args: &ShowArgs) -> AResult<i32> {
     show_orefs(&ibundle.moved_orefs);
     if let Some(unchanged_orefs) = &ibundle.unchanged_orefs {
         log::info!("unchanged_orefs: {}", unchanged_orefs.len());
-        show_orefs(&unchanged_orefs);
+        show_orefs(unchanged_orefs);
     }
     log::info!("prereqs: {}", ibundle.prereqs.len());
     show_commits(&ibundle.prereqs);
+
+    report.set("ibundle_path", ibundle_path.to_string_lossy().as_ref());
+    report.set("standalone", ibundle.unchanged_orefs.is_some());
+    report.set("repo_id", json_bstr(ibundle.repo_id.as_bstr()));
+    report.set("seq_num", ibundle.seq_num);
+    report.set("basis_seq_num", ibundle.basis_seq_num);
+    report.set("head_ref", json_bstr(ibundle.head_ref.as_bstr()));
+    report.set("head_detached", ibundle.head_detached);
+    report.set("added_orefs", json_orefs(&ibundle.added_orefs));
+    report.set("removed_orefs", json_orefs(&ibundle.removed_orefs));
+    report.set("moved_orefs", json_orefs(&ibundle.moved_orefs));
+    report.set(
+        "unchanged_orefs",
+        ibundle.unchanged_orefs.as_ref().map(json_orefs),
+    );
+    report.set("packed_orefs", json_orefs(&ibundle.packed_orefs));
+    report.set("prereqs", json_commits(&ibundle.prereqs));
+    Ok(STATUS_OK)
+}
+
+fn cmd_approve(
+    approve_args: &ApproveArgs,
+    report: &mut Report,
+) -> AResult<i32> {
+    let ibundle_path = &approve_args.ibundle_path;
+    let (ibundle, mut ibundle_reader) = read_ibundle(ibundle_path)?;
+    let ibundle_sha256 = reader_sha256(&mut ibundle_reader)?;
+    drop(ibundle_reader);
+    let signature = ssh_keygen_sign(
+        &approve_args.key,
+        approval_message(&ibundle_sha256).as_bytes(),
+    )?;
+
+    let approvals_path = approvals_path(ibundle_path);
+    let mut approvals_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&approvals_path)
+        .with_context(|| {
+            format!("failed to open {}", quoted_path(&approvals_path))
+        })?;
+    approvals_file.write_all(&signature)?;
+    log::info!(
+        "approved {} ({}; sha256 {}) in {}",
+        quoted_path(ibundle_path),
+        ibundle.summary(),
+        ibundle_sha256,
+        quoted_path(&approvals_path)
+    );
+    report.set("ibundle_path", ibundle_path.to_string_lossy().as_ref());
+    report.set("seq_num", ibundle.seq_num);
+    report.set("sha256", ibundle_sha256.as_str());
+    report.set("approvals_path", approvals_path.to_string_lossy().as_ref());
     Ok(STATUS_OK)
 }
 
-fn cmd_status(status_args: &StatusArgs) -> AResult<i32> {
+fn cmd_status(
+    status_args: &StatusArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
     let _ = status_args;
-    let repo_path = ".";
     let repo = repo_open(repo_path)?;
-    let mut failed = false;
+    let mut num_failed = 0;
 
-    let repo_id = repo_id_read(&repo).unwrap_or(BString::from("NONE"));
+    let repo_id = repo_id_read(&repo);
     let seq_nums = repo_seq_nums(&repo)?;
     let max_seq_num = calc_max_seq_num(&seq_nums)?;
     let next_seq_num = calc_next_seq_num(&seq_nums)?;
 
-    log::info!("repo_id: {}", repo_id);
+    log::info!(
+        "repo_id: {}",
+        repo_id.clone().unwrap_or(BString::from("NONE"))
+    );
     log::info!("max_seq_num: {}", max_seq_num);
     log::info!("next_seq_num: {}", next_seq_num);
     log::debug!("kept_seq_nums: {}", seq_nums.len());
+    let state_root_path = repo_state_root_path(&repo)?;
+    log::debug!("state_dir: {}", quoted_path(&state_root_path));
+    report.set("state_dir", state_root_path.to_string_lossy().as_ref());
+    log::debug!("backend: {}", backend().name());
+    report.set("backend", backend().name());
+    report.set(
+        "repo_id",
+        repo_id.as_ref().map(|id| json_bstr(id.as_bstr())),
+    );
+    report.set("max_seq_num", max_seq_num);
+    report.set("next_seq_num", next_seq_num);
+
+    if log_enabled!(Level::Debug) || report.is_json() {
+        log::debug!("config:");
+        let mut config = serde_json::Map::new();
+        for &(name, kind) in CONFIG_KEYS {
+            let (values, is_default) =
+                repo_config_effective(&repo, name, kind)?;
+            if values.is_empty() {
+                log::debug!("  {} = (unset)", name);
+            }
+            for value in values.iter() {
+                if is_default {
+                    log::debug!("  {} = {} (default)", name, value);
+                } else {
+                    log::debug!("  {} = {}", name, value);
+                }
+            }
+            let values = values
+                .iter()
+                .map(|value| json_bstr(value.as_bstr()))
+                .collect::<Vec<_>>();
+            config.insert(
+                name.to_string(),
+                if matches!(kind, ConfigKind::MultiVar) {
+                    values.into()
+                } else {
+                    values.into_iter().next().into()
+                },
+            );
+        }
+        report.set("config", config);
 
-    if log_enabled!(Level::Debug) {
-        if seq_nums.len() > 0 {
+        if !seq_nums.is_empty() {
             log::debug!("  {:<8} {:<8} {}", "seq_num", "num_refs", "HEAD");
-            for &seq_num in seq_nums.iter().rev() {
-                match repo_meta_read(&repo, seq_num) {
-                    Ok(meta) => {
-                        log::debug!(
-                            "  {:<8} {:<8} {}{}",
-                            seq_num,
-                            meta.orefs.len(),
-                            meta.head_ref,
-                            if meta.head_detached {
-                                " (detached)"
-                            } else {
-                                ""
-                            }
-                        );
-                    }
-                    Err(e) => {
-                        log::debug!("  {:<8} **Error: {}", seq_num, e);
-                        failed = true;
-                    }
+        }
+        let mut seq_details = Vec::new();
+        for &seq_num in seq_nums.iter().rev() {
+            match repo_meta_read(&repo, seq_num) {
+                Ok(meta) => {
+                    log::debug!(
+                        "  {:<8} {:<8} {}{}",
+                        seq_num,
+                        meta.orefs.len(),
+                        meta.head_ref,
+                        if meta.head_detached {
+                            " (detached)"
+                        } else {
+                            ""
+                        }
+                    );
+                    seq_details.push(serde_json::json!({
+                        "seq_num": seq_num,
+                        "num_refs": meta.orefs.len(),
+                        "head_ref": json_bstr(meta.head_ref.as_bstr()),
+                        "head_detached": meta.head_detached,
+                    }));
+                }
+                Err(e) => {
+                    log::debug!("  {:<8} **Error: {}", seq_num, e);
+                    seq_details.push(serde_json::json!({
+                        "seq_num": seq_num,
+                        "error": format!("{:#}", e),
+                    }));
+                    num_failed += 1;
                 }
             }
         }
+        report.set("seq_nums", seq_details);
     } else {
         log::info!("Use `--verbose` for details.");
     }
 
-    Ok(if failed { STATUS_ERROR } else { STATUS_OK })
+    if num_failed > 0 {
+        report.message = Some(format!(
+            "cannot read metadata for {} sequence numbers",
+            num_failed
+        ));
+        return Ok(STATUS_ERROR);
+    }
+    Ok(STATUS_OK)
 }
 
-fn cmd_clean(clean_args: &CleanArgs) -> AResult<i32> {
-    let repo_path = ".";
+fn cmd_clean(
+    clean_args: &CleanArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
     let repo = repo_open(repo_path)?;
 
     if repo_id_read(&repo).is_none() {
         bail!("missing repo_id; no sequence numbers to clean");
     }
     let mut seq_nums = repo_seq_nums(&repo)?;
-    let keep = usize::try_from(clean_args.keep).unwrap_or(usize::MAX);
+    let keep = match clean_args.keep {
+        Some(keep) => keep,
+        None => match repo_config_u64(&repo, "ibundle.keep")? {
+            Some(0) => bail!("ibundle.keep must be at least 1"),
+            Some(keep) => keep,
+            None => DEFAULT_KEEP,
+        },
+    };
+    let keep = usize::try_from(keep).unwrap_or(usize::MAX);
+    report.set("num_seq_nums", seq_nums.len());
+    report.set("num_removed", seq_nums.len().saturating_sub(keep));
     if seq_nums.len() <= keep {
         log::info!(
             "have {} sequence numbers, keeping up to {} => nothing to clean",
@@ -1720,11 +5971,11 @@ fn cmd_clean(clean_args: &CleanArgs) -> AResult<i32> {
             keep,
             seq_nums.len() - keep,
         );
-        let meta_dir_path = repo_meta_dir_path(&repo);
+        let meta_dir_path = repo_meta_dir_path(&repo)?;
 
         while seq_nums.len() > keep {
             if let Some(seq_num) = seq_nums.pop() {
-                let meta_path = meta_dir_path.join(&seq_num.to_string());
+                let meta_path = meta_dir_path.join(seq_num.to_string());
                 fs::remove_file(&meta_path).with_context(|| {
                     format!(
                         "failed to remove seq_num {} at {}",
@@ -1736,35 +5987,767 @@ fn cmd_clean(clean_args: &CleanArgs) -> AResult<i32> {
         }
     }
 
+    if let Some(seq_num) = clean_args.archive_older_than {
+        let num_removed = repo_expire_archive_refs(&repo, seq_num)?;
+        log::info!(
+            "removed {} archive refs older than seq_num {}",
+            num_removed,
+            seq_num
+        );
+        report.set("num_archive_refs_removed", num_removed);
+    } else {
+        report.set("num_archive_refs_removed", serde_json::Value::Null);
+    }
+
     Ok(STATUS_OK)
 }
 
-fn run() -> AResult<i32> {
-    let cli = Cli::parse();
-    env_logger::Builder::new()
-        .filter_level(cli.verbose.log_level_filter())
-        .format(|buf, record| writeln!(buf, "{}", record.args()))
-        .target(env_logger::Target::Stdout)
-        .init();
+fn cmd_outbox_prune(
+    prune_args: &OutboxPruneArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
+    if prune_args.dry_run {
+        log::info!("(dry run)");
+    }
+    let repo = repo_open(repo_path)?;
+    let repo_id = match repo_id_read(&repo) {
+        Some(repo_id) => repo_id,
+        None => bail!("missing repo_id; no ibundles to prune"),
+    };
+    let outbox_path = &prune_args.outbox_path;
+    let entries = catalog_read(outbox_path)?;
+    let (pruned, kept): (Vec<_>, Vec<_>) =
+        entries.iter().cloned().partition(|entry| {
+            entry.repo_id == repo_id
+                && (prune_args
+                    .acknowledged
+                    .is_some_and(|seq_num| entry.seq_num <= seq_num)
+                    || catalog_entry_superseded(entry, &entries))
+        });
+    let mut pruned_names = Vec::new();
+    for entry in pruned.iter() {
+        let ibundle_path = outbox_path.join(entry.file_name.to_os_str()?);
+        log::info!(
+            "pruning {} (seq_num {}, basis_seq_num {})",
+            quoted_path(&ibundle_path),
+            entry.seq_num,
+            entry.basis_seq_num
+        );
+        pruned_names.push(json_bstr(entry.file_name.as_bstr()));
+        if prune_args.dry_run {
+            continue;
+        }
+        for path in [approvals_path(&ibundle_path), ibundle_path] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| {
+                        format!("failed to remove {}", quoted_path(&path))
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    if !prune_args.dry_run && !pruned.is_empty() {
+        catalog_write(outbox_path, &kept)?;
+    }
+    log::info!("pruned {} ibundles, kept {}", pruned.len(), kept.len());
+    report.set("pruned", pruned_names);
+    report.set("num_kept", kept.len());
+    report.set("dry_run", prune_args.dry_run);
+    Ok(STATUS_OK)
+}
+
+fn cmd_outbox(
+    outbox_args: &OutboxArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
+    match &outbox_args.command {
+        OutboxCommands::Prune(prune_args) => {
+            cmd_outbox_prune(prune_args, repo_path, report)
+        }
+    }
+}
+
+fn cmd_archive_create(
+    create_args: &ArchiveCreateArgs,
+    report: &mut Report,
+) -> AResult<i32> {
+    let mut entries = Vec::new();
+    for ibundle_path in create_args.ibundle_paths.iter() {
+        let (ibundle, _) = read_ibundle(ibundle_path)?;
+        if entries.iter().any(|entry: &ArchiveEntry| {
+            entry.repo_id == ibundle.repo_id && entry.seq_num == ibundle.seq_num
+        }) {
+            bail!(
+                "duplicate ibundle for repo_id {} seq_num {}: {}",
+                ibundle.repo_id,
+                ibundle.seq_num,
+                quoted_path(ibundle_path)
+            );
+        }
+        let name = match ibundle_path.file_name() {
+            Some(name) => BString::from(name.to_string_lossy().as_ref()),
+            None => bail!("invalid ibundle path {}", quoted_path(ibundle_path)),
+        };
+        log::info!(
+            "adding {} (repo_id {}, seq_num {})",
+            quoted_path(ibundle_path),
+            ibundle.repo_id,
+            ibundle.seq_num
+        );
+        let approvals_path = approvals_path(ibundle_path);
+        let approvals_size = if approvals_path.exists() {
+            fs::metadata(&approvals_path)?.len()
+        } else {
+            0
+        };
+        entries.push(ArchiveEntry {
+            repo_id: ibundle.repo_id,
+            seq_num: ibundle.seq_num,
+            basis_seq_num: ibundle.basis_seq_num,
+            size: fs::metadata(ibundle_path)?.len(),
+            approvals_size,
+            sha256: file_sha256(ibundle_path)?,
+            name,
+            offset: 0,
+        });
+    }
+
+    let archive_path = &create_args.archive_path;
+    let mut temp_path = archive_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = path::PathBuf::from(temp_path);
+    let _temp_deleter = FileDeleter::new(&temp_path);
+    let mut writer = create_writer(&temp_path)?;
+    write_bline(&mut writer, ARCHIVE_FORMAT_V1.as_bstr())?;
+    for entry in entries.iter() {
+        writeln!(
+            writer,
+            "{} {} {} {} {} {} {}",
+            entry.repo_id,
+            entry.seq_num,
+            entry.basis_seq_num,
+            entry.size,
+            entry.approvals_size,
+            entry.sha256,
+            entry.name
+        )?;
+    }
+    writer.write_all(b"\n")?;
+    for (ibundle_path, entry) in
+        create_args.ibundle_paths.iter().zip(entries.iter())
+    {
+        let size = io::copy(
+            &mut open_file(ibundle_path)?.take(entry.size),
+            &mut writer,
+        )?;
+        if size != entry.size {
+            bail!("{} changed size", quoted_path(ibundle_path));
+        }
+        if entry.approvals_size > 0 {
+            let approvals_path = approvals_path(ibundle_path);
+            let size = io::copy(
+                &mut open_file(&approvals_path)?.take(entry.approvals_size),
+                &mut writer,
+            )?;
+            if size != entry.approvals_size {
+                bail!("{} changed size", quoted_path(&approvals_path));
+            }
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&temp_path, archive_path)?;
+    log::info!(
+        "wrote {} ibundles to {}",
+        entries.len(),
+        quoted_path(archive_path)
+    );
+    report.set("archive_path", archive_path.to_string_lossy());
+    report.set("ibundles", json_archive_entries(&entries));
+    Ok(STATUS_OK)
+}
+
+fn cmd_archive_list(
+    list_args: &ArchiveListArgs,
+    report: &mut Report,
+) -> AResult<i32> {
+    let (entries, _) = archive_read(&list_args.archive_path)?;
+    if !report.is_json() {
+        for entry in entries.iter() {
+            println!(
+                "{} {} {} {} {}",
+                entry.repo_id,
+                entry.seq_num,
+                entry.basis_seq_num,
+                entry.size,
+                entry.name
+            );
+        }
+    }
+    report.set("ibundles", json_archive_entries(&entries));
+    Ok(STATUS_OK)
+}
+
+fn cmd_archive(
+    archive_args: &ArchiveArgs,
+    report: &mut Report,
+) -> AResult<i32> {
+    match &archive_args.command {
+        ArchiveCommands::Create(create_args) => {
+            cmd_archive_create(create_args, report)
+        }
+        ArchiveCommands::List(list_args) => cmd_archive_list(list_args, report),
+    }
+}
+
+// Ibundle files in `inbox_path` unmodified for at least `settle`; partial
+// downloads (`*.part`, `*.tmp`), hidden files, and approvals are skipped.
+fn inbox_ready_files(
+    inbox_path: &path::Path,
+    settle: std::time::Duration,
+) -> AResult<Vec<path::PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(inbox_path).with_context(|| {
+        format!("failed to read inbox {}", quoted_path(inbox_path))
+    })? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.')
+            || [".part", ".tmp", ".approvals", ".error"]
+                .iter()
+                .any(|suffix| name.ends_with(suffix))
+        {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if metadata.is_file() && age >= settle {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// Moves `ibundle_path` (and its approvals, if any) into the `dir_name`
+// subdirectory of `inbox_path`, returning the new path.
+fn inbox_move(
+    inbox_path: &path::Path,
+    ibundle_path: &path::Path,
+    dir_name: &str,
+) -> AResult<path::PathBuf> {
+    let dir_path = inbox_path.join(dir_name);
+    fs::create_dir_all(&dir_path)?;
+    let mut new_path = dir_path.clone();
+    for path in [approvals_path(ibundle_path), ibundle_path.to_path_buf()] {
+        if path.exists() {
+            new_path = dir_path.join(path.file_name().unwrap_or_default());
+            fs::rename(&path, &new_path).with_context(|| {
+                format!("failed to move {}", quoted_path(&path))
+            })?;
+        }
+    }
+    Ok(new_path)
+}
+
+// Moves a failed ibundle to `quarantine/` alongside a `.error` report.
+fn inbox_quarantine(
+    inbox_path: &path::Path,
+    ibundle_path: &path::Path,
+    status: i32,
+    message: &str,
+) -> AResult<()> {
+    let new_path = inbox_move(inbox_path, ibundle_path, "quarantine")?;
+    let mut error_path = new_path.into_os_string();
+    error_path.push(".error");
+    fs::write(
+        &error_path,
+        format!(
+            "status: {}\ncode: {}\nerror: {}\n",
+            status,
+            status_code(status),
+            message
+        ),
+    )?;
+    log::warn!("quarantined {}: {}", quoted_path(ibundle_path), message);
+    Ok(())
+}
+
+// Outcome of watching one inbox file, for `--format json`.
+fn watch_result(
+    ibundle_path: &path::Path,
+    seq_num: Option<SeqNum>,
+    outcome: &str,
+    error: Option<(i32, &str)>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "file": ibundle_path.file_name().unwrap_or_default().to_string_lossy(),
+        "seq_num": seq_num,
+        "outcome": outcome,
+        "error": error.map(|(status, message)| serde_json::json!({
+            "code": status_code(status),
+            "status": status,
+            "message": message,
+        })),
+    })
+}
+
+// Fetches each complete ibundle in the inbox in `seq_num` order, holding back
+// those whose basis has yet to arrive.  Returns a result per ibundle file.
+fn watch_inbox(
+    watch_args: &WatchArgs,
+    repo_path: &path::Path,
+) -> AResult<Vec<serde_json::Value>> {
+    let inbox_path = &watch_args.inbox;
+    let settle = std::time::Duration::from_secs(watch_args.settle);
+    let mut results = Vec::new();
+    let mut pending = Vec::new();
+    for ibundle_path in inbox_ready_files(inbox_path, settle)? {
+        match read_ibundle(&ibundle_path) {
+            Ok((ibundle, _)) => pending.push((ibundle, ibundle_path)),
+            Err(e) => {
+                let message = format!("{:#}", e);
+                inbox_quarantine(
+                    inbox_path,
+                    &ibundle_path,
+                    STATUS_ERROR,
+                    &message,
+                )?;
+                results.push(watch_result(
+                    &ibundle_path,
+                    None,
+                    "quarantined",
+                    Some((STATUS_ERROR, &message)),
+                ));
+            }
+        }
+    }
+    pending.sort_by_key(|(ibundle, _)| ibundle.seq_num);
+
+    for (ibundle, ibundle_path) in pending {
+        let repo = repo_open(repo_path)?;
+        if ibundle.basis_seq_num != 0
+            && !repo_has_basis(&repo, &ibundle.basis_seq_num)
+        {
+            log::info!(
+                "holding {} (seq_num {}) until basis_seq_num {} arrives",
+                quoted_path(&ibundle_path),
+                ibundle.seq_num,
+                ibundle.basis_seq_num
+            );
+            results.push(watch_result(
+                &ibundle_path,
+                Some(ibundle.seq_num),
+                "held",
+                None,
+            ));
+            continue;
+        }
+
+        log::info!("fetching {}", quoted_path(&ibundle_path));
+        let fetch_args = FetchArgs {
+            ibundle_path: ibundle_path.clone(),
+            archive: false,
+            repos_dir: None,
+            repo_map: None,
+            dry_run: false,
+            force: false,
+            checks: watch_args.checks.clone(),
+            porcelain: false,
+        };
+        let (status, message) = fetch_capture(&fetch_args, repo_path);
+        if status == STATUS_OK {
+            inbox_move(inbox_path, &ibundle_path, "done")?;
+            results.push(watch_result(
+                &ibundle_path,
+                Some(ibundle.seq_num),
+                "applied",
+                None,
+            ));
+        } else {
+            inbox_quarantine(inbox_path, &ibundle_path, status, &message)?;
+            results.push(watch_result(
+                &ibundle_path,
+                Some(ibundle.seq_num),
+                "quarantined",
+                Some((status, &message)),
+            ));
+        }
+    }
+    Ok(results)
+}
+
+fn cmd_watch(
+    watch_args: &WatchArgs,
+    repo_path: &path::Path,
+    report: &mut Report,
+) -> AResult<i32> {
+    loop {
+        let results = watch_inbox(watch_args, repo_path)?;
+        if watch_args.once {
+            let num_quarantined = results
+                .iter()
+                .filter(|result| result["outcome"] == "quarantined")
+                .count();
+            report.set("files", results);
+            if num_quarantined > 0 {
+                report.message =
+                    Some(format!("quarantined {} ibundles", num_quarantined));
+                return Ok(STATUS_ERROR);
+            }
+            return Ok(STATUS_OK);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(watch_args.interval));
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+
+// A fleet manifest lists one repository per line:
+//
+//   # Comment.
+//   <name> <repo_path> [ibundle=<FILE>] [policy=<POLICY_FILE>]
+//       [identity-policy=<IDENTITY_POLICY_FILE>] [include-refs=<PATTERN>]...
+//       [exclude-refs=<PATTERN>]...
+//
+// Relative paths are relative to the manifest's directory.  Each repository
+// may appear only once.
+#[derive(Debug, Clone)]
+struct FleetEntry {
+    name: String,
+    repo_path: path::PathBuf,
+    ibundle_path: Option<path::PathBuf>,
+    policy_path: Option<path::PathBuf>,
+    identity_policy_path: Option<path::PathBuf>,
+    include_refs: Vec<String>,
+    exclude_refs: Vec<String>,
+}
+
+fn fleet_manifest_read(manifest_path: &path::Path) -> AResult<Vec<FleetEntry>> {
+    let base_path = std::env::current_dir()?
+        .join(manifest_path.parent().unwrap_or(path::Path::new("")));
+    let mut data = Vec::new();
+    open_reader(manifest_path)?.read_to_end(&mut data)?;
+    let mut entries: Vec<FleetEntry> = Vec::new();
+    for (i, line) in ByteSlice::lines(data.as_slice()).enumerate() {
+        let line = line.trim().as_bstr();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let context = || format!("fleet manifest line {}", i + 1);
+        let mut fields = line.fields();
+        let (name, repo_path) = match (fields.next(), fields.next()) {
+            (Some(name), Some(repo_path)) => (name, repo_path),
+            _ => bail!("{}: expected <name> <repo_path>", context()),
+        };
+        let name = name.to_str().with_context(context)?.to_string();
+        if entries.iter().any(|entry| entry.name == name) {
+            bail!("{}: duplicate name {}", context(), quoted(name.as_bytes()));
+        }
+        let mut entry = FleetEntry {
+            name,
+            repo_path: base_path.join(repo_path.to_path()?),
+            ibundle_path: None,
+            policy_path: None,
+            identity_policy_path: None,
+            include_refs: Vec::new(),
+            exclude_refs: Vec::new(),
+        };
+        for field in fields {
+            let (key, value) = match field.split_once_str(b"=") {
+                Some((key, value)) => (key.as_bstr(), value),
+                None => bail!("{}: expected <key>=<value>", context()),
+            };
+            if key == "include-refs" || key == "exclude-refs" {
+                let pattern = value.to_str().with_context(context)?.to_string();
+                if key == "include-refs" {
+                    entry.include_refs.push(pattern);
+                } else {
+                    entry.exclude_refs.push(pattern);
+                }
+                continue;
+            }
+            let value = Some(base_path.join(value.to_path()?));
+            if key == "ibundle" {
+                entry.ibundle_path = value;
+            } else if key == "policy" {
+                entry.policy_path = value;
+            } else if key == "identity-policy" {
+                entry.identity_policy_path = value;
+            } else {
+                bail!("{}: unknown key {}", context(), quoted(key));
+            }
+        }
+        // Compare repositories that exist by their canonical paths.
+        let canonical_path = |entry: &FleetEntry| {
+            fs::canonicalize(&entry.repo_path)
+                .unwrap_or_else(|_| entry.repo_path.clone())
+        };
+        let repo_path = canonical_path(&entry);
+        if let Some(other) = entries
+            .iter()
+            .find(|other| canonical_path(other) == repo_path)
+        {
+            bail!(
+                "{}: repository {} duplicates that of {}",
+                context(),
+                quoted_path(&entry.repo_path),
+                quoted(other.name.as_bytes())
+            );
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+thread_local! {
+    // Name of the fleet entry being processed, prefixed to log messages.
+    static LOG_PREFIX: std::cell::RefCell<Option<String>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+// Runs `create` or `fetch` for one fleet entry, returning its exit status,
+// error message, and command report.
+fn fleet_run_entry(
+    command: &FleetCommands,
+    entry: &FleetEntry,
+) -> (i32, String, Report) {
+    let mut entry_report = Report::new(OutputFormat::Text, "fleet");
+    let result = match command {
+        FleetCommands::Create(_) => {
+            let create_args = CreateArgs {
+                ibundle_path: entry.ibundle_path.clone(),
+                outbox: None,
+                basis: None,
+                basis_current: false,
+                standalone: false,
+                no_standalone: false,
+                allow_empty: false,
+                include_refs: entry.include_refs.clone(),
+                exclude_refs: entry.exclude_refs.clone(),
+                policy: entry.policy_path.clone(),
+                manifest: None,
+                allow_mass_changes: false,
+                porcelain: false,
+            };
+            cmd_create(&create_args, &entry.repo_path, &mut entry_report)
+        }
+        FleetCommands::Fetch(_) => match &entry.ibundle_path {
+            Some(ibundle_path) if !ibundle_path.exists() => {
+                // Nothing was created for this repository.
+                log::info!("no ibundle {}", quoted_path(ibundle_path));
+                Ok(STATUS_EMPTY_BUNDLE)
+            }
+            Some(ibundle_path) => {
+                let fetch_args = FetchArgs {
+                    ibundle_path: ibundle_path.clone(),
+                    archive: false,
+                    repos_dir: None,
+                    repo_map: None,
+                    dry_run: false,
+                    force: false,
+                    checks: FetchCheckArgs {
+                        identity_policy: entry.identity_policy_path.clone(),
+                        ..Default::default()
+                    },
+                    porcelain: false,
+                };
+                cmd_fetch(&fetch_args, &entry.repo_path, &mut entry_report)
+            }
+            None => Err(anyhow!("no ibundle file in fleet manifest")),
+        },
+    };
+    match result {
+        Ok(status) => {
+            let message = entry_report.message.clone().unwrap_or_default();
+            (status, message, entry_report)
+        }
+        Err(e) => (error_status(&e), format!("{:#}", e), entry_report),
+    }
+}
+
+fn cmd_fleet(fleet_args: &FleetArgs, report: &mut Report) -> AResult<i32> {
+    let command = &fleet_args.command;
+    let run_args = match command {
+        FleetCommands::Create(run_args) | FleetCommands::Fetch(run_args) => {
+            run_args
+        }
+    };
+    let entries =
+        fleet_manifest_read(&run_args.manifest_path).with_context(|| {
+            format!(
+                "failure reading fleet manifest {}",
+                quoted_path(&run_args.manifest_path)
+            )
+        })?;
+
+    // Workers take entries in manifest order until none remain.
+    let next_index = std::sync::atomic::AtomicUsize::new(0);
+    let results = std::sync::Mutex::new(Vec::new());
+    let num_jobs = usize::try_from(run_args.jobs)?.min(entries.len());
+    std::thread::scope(|scope| {
+        for _ in 0..num_jobs {
+            scope.spawn(|| loop {
+                let index = next_index
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let Some(entry) = entries.get(index) else {
+                    break;
+                };
+                LOG_PREFIX.with(|prefix| {
+                    *prefix.borrow_mut() = Some(entry.name.clone())
+                });
+                let (status, message, entry_report) =
+                    fleet_run_entry(command, entry);
+                if status != STATUS_OK && status != STATUS_EMPTY_BUNDLE {
+                    log::warn!("failed: {}", message);
+                }
+                LOG_PREFIX.with(|prefix| *prefix.borrow_mut() = None);
+                results.lock().unwrap().push((
+                    index,
+                    status,
+                    message,
+                    entry_report,
+                ));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, ..)| *index);
+
+    let name_width = entries.iter().map(|entry| entry.name.len()).max();
+    let name_width = name_width.unwrap_or(0);
+    let mut repos = Vec::new();
+    let mut num_failed = 0;
+    log::info!("fleet results:");
+    for (index, status, message, entry_report) in results {
+        let entry = &entries[index];
+        let seq_num = entry_report.result.get("seq_num").cloned();
+        let outcome = match status {
+            STATUS_OK => "ok",
+            STATUS_EMPTY_BUNDLE => "nothing_to_do",
+            _ => {
+                num_failed += 1;
+                "failed"
+            }
+        };
+        let detail = match (&seq_num, outcome) {
+            (Some(seq_num), "ok") => format!("seq_num {}", seq_num),
+            (_, "failed") => format!("[{}] {}", status_code(status), message),
+            _ => String::new(),
+        };
+        log::info!(
+            "  {:name_width$}  {:13}  {}",
+            entry.name,
+            outcome,
+            detail,
+            name_width = name_width
+        );
+        repos.push(serde_json::json!({
+            "name": entry.name,
+            "repo": entry.repo_path.to_string_lossy(),
+            "outcome": outcome,
+            "seq_num": seq_num,
+            "error": if outcome == "failed" {
+                serde_json::json!({
+                    "code": status_code(status),
+                    "status": status,
+                    "message": message,
+                })
+            } else {
+                serde_json::Value::Null
+            },
+        }));
+    }
+    log::info!(
+        "{} repositories: {} ok, {} nothing to do, {} failed",
+        entries.len(),
+        repos.iter().filter(|repo| repo["outcome"] == "ok").count(),
+        repos
+            .iter()
+            .filter(|repo| repo["outcome"] == "nothing_to_do")
+            .count(),
+        num_failed
+    );
+    report.set("repos", repos);
+    if num_failed > 0 {
+        report.message = Some(format!("{} repositories failed", num_failed));
+        return Ok(STATUS_ERROR);
+    }
+    Ok(STATUS_OK)
+}
+
+fn run(cli: &Cli, report: &mut Report) -> AResult<i32> {
+    let repo_path = cli.repo.as_deref().unwrap_or(path::Path::new("."));
+    select_state_dir(cli.state_dir.as_deref())?;
+    if BACKEND
+        .set(backend_for(backend_kind(cli.backend)?)?)
+        .is_err()
+    {
+        bail!("backend already selected");
+    }
     let exit_status = match &cli.command {
-        Commands::Create(create_args) => cmd_create(create_args)?,
-        Commands::Fetch(fetch_args) => cmd_fetch(fetch_args)?,
-        Commands::Show(show_args) => cmd_show(show_args)?,
-        Commands::Status(status_args) => cmd_status(status_args)?,
-        Commands::Clean(clean_args) => cmd_clean(clean_args)?,
+        Commands::Create(create_args) => {
+            cmd_create(create_args, repo_path, report)?
+        }
+        Commands::Fetch(fetch_args) => {
+            cmd_fetch(fetch_args, repo_path, report)?
+        }
+        Commands::Show(show_args) => cmd_show(show_args, repo_path, report)?,
+        Commands::Approve(approve_args) => cmd_approve(approve_args, report)?,
+        Commands::Status(status_args) => {
+            cmd_status(status_args, repo_path, report)?
+        }
+        Commands::Clean(clean_args) => {
+            cmd_clean(clean_args, repo_path, report)?
+        }
+        Commands::Outbox(outbox_args) => {
+            cmd_outbox(outbox_args, repo_path, report)?
+        }
+        Commands::Watch(watch_args) => {
+            cmd_watch(watch_args, repo_path, report)?
+        }
+        Commands::Archive(archive_args) => cmd_archive(archive_args, report)?,
+        Commands::Fleet(fleet_args) => cmd_fleet(fleet_args, report)?,
     };
     Ok(exit_status)
 }
 
 fn main() {
-    let exit_status = match run() {
+    let cli = Cli::parse();
+    let mut report = Report::new(cli.format, cli.command.name());
+    // Keep stdout free for the JSON document.
+    env_logger::Builder::new()
+        .filter_level(cli.verbose.log_level_filter())
+        .format(|buf, record| {
+            let prefix = LOG_PREFIX.with(|prefix| prefix.borrow().clone());
+            match prefix {
+                Some(prefix) => writeln!(buf, "{}: {}", prefix, record.args()),
+                None => writeln!(buf, "{}", record.args()),
+            }
+        })
+        .target(if report.is_json() {
+            env_logger::Target::Stderr
+        } else {
+            env_logger::Target::Stdout
+        })
+        .init();
+    let mut exit_status = match run(&cli, &mut report) {
         Ok(exit_status) => exit_status,
         Err(e) => {
             if log_enabled!(Level::Error) {
                 eprintln!("error: {:?}", e);
             }
-            STATUS_ERROR
+            report.message = Some(format!("{:#}", e));
+            error_status(&e)
         }
     };
+    if let Err(e) = report.write(exit_status) {
+        eprintln!("error: {:?}", e);
+        exit_status = STATUS_ERROR;
+    }
     std::process::exit(exit_status);
 }